@@ -0,0 +1,178 @@
+use super::Backend;
+use core::{alloc::Layout, ptr};
+
+/// Size of the smallest block, of order 0.
+const MIN_BLOCK_SIZE: usize = 16;
+/// Blocks of the highest order are 2 MiB, the granularity the heap grows
+/// with.
+const MAX_ORDER: usize = 17;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+/// Splits the heap into power of two blocks and merges freed blocks with
+/// their buddies.
+///
+/// Blocks are aligned to their size relative to the start of the heap,
+/// which therefore has to be aligned to the largest block.
+pub struct BuddyAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    free_lists: [*mut FreeBlock; MAX_ORDER + 1],
+    used: usize,
+}
+
+// the free lists point into the heap, which only the owner touches
+unsafe impl Send for BuddyAllocator {}
+
+fn block_size(order: usize) -> usize {
+    MIN_BLOCK_SIZE << order
+}
+
+fn order_for(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_BLOCK_SIZE)
+        .checked_next_power_of_two()?;
+    let order = (size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+    if order <= MAX_ORDER {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            free_lists: [ptr::null_mut(); MAX_ORDER + 1],
+            used: 0,
+        }
+    }
+
+    /// Number of free blocks of `order`.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut block = self.free_lists[order];
+        while !block.is_null() {
+            count += 1;
+            block = unsafe { (*block).next };
+        }
+        count
+    }
+
+    /// Adds `start..end`, right after the current end of the heap, in the
+    /// largest blocks its alignment allows.
+    unsafe fn add_range(&mut self, start: usize, end: usize) {
+        self.heap_end = end;
+        let mut addr = start;
+        while let Some(order) = (0..=MAX_ORDER).rev().find(|&order| {
+            (addr - self.heap_start) % block_size(order) == 0 && addr + block_size(order) <= end
+        }) {
+            self.free_block(addr, order);
+            addr += block_size(order);
+        }
+    }
+
+    unsafe fn push(&mut self, order: usize, addr: usize) {
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock {
+            next: self.free_lists[order],
+        });
+        self.free_lists[order] = block;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order];
+        if block.is_null() {
+            return None;
+        }
+        self.free_lists[order] = unsafe { (*block).next };
+        Some(block as usize)
+    }
+
+    /// Takes the block at `addr` out of the free list of `order`, if it is
+    /// in there.
+    unsafe fn remove(&mut self, order: usize, addr: usize) -> bool {
+        let mut link: *mut *mut FreeBlock = &mut self.free_lists[order];
+        while !(*link).is_null() {
+            if *link as usize == addr {
+                *link = (**link).next;
+                return true;
+            }
+            link = &mut (**link).next;
+        }
+        false
+    }
+
+    /// Frees the block at `addr`, merging it with its buddy as long as that
+    /// is free too.
+    unsafe fn free_block(&mut self, mut addr: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = self.heap_start + ((addr - self.heap_start) ^ block_size(order));
+            if buddy + block_size(order) > self.heap_end || !self.remove(order, buddy) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(order, addr);
+    }
+}
+
+impl Backend for BuddyAllocator {
+    const NAME: &'static str = "buddy";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.add_range(heap_start, heap_start + heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.add_range(self.heap_end, self.heap_end + by);
+    }
+
+    /// The largest block, of `MAX_ORDER`.
+    fn max_size(&self) -> usize {
+        block_size(MAX_ORDER)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let order = match order_for(&layout) {
+            Some(order) => order,
+            None => return ptr::null_mut(),
+        };
+        let (mut block_order, block) = match (order..=MAX_ORDER)
+            .find_map(|block_order| Some((block_order, self.pop(block_order)?)))
+        {
+            Some(found) => found,
+            None => return ptr::null_mut(),
+        };
+        // give back the upper halves until the block has the right size
+        while block_order > order {
+            block_order -= 1;
+            unsafe { self.push(block_order, block + block_size(block_order)) };
+        }
+        self.used += block_size(order);
+        block as *mut u8
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let order = order_for(&layout).unwrap();
+        self.used -= block_size(order);
+        self.free_block(ptr as usize, order);
+    }
+
+    fn top(&self) -> usize {
+        self.heap_end
+    }
+
+    /// Bytes of allocated blocks, including the rounding to powers of two.
+    fn used(&self) -> usize {
+        self.used
+    }
+}
@@ -0,0 +1,121 @@
+//! Checks for heap corruption, enabled by the `heap-debug` feature.
+//!
+//! Every allocation is wrapped like this:
+//!
+//! ```text
+//! | padding | header | red zone | object | red zone |
+//! ```
+//!
+//! The red zones are filled with a pattern that is checked on free, and
+//! freed objects are overwritten with a poison pattern, so that stale
+//! pointers read garbage instead of plausible data. The header records the
+//! layout, which has to match on free, and whether the allocation is still
+//! live, which catches double frees as long as the block is not reused.
+
+use core::{
+    alloc::Layout,
+    mem::{align_of, size_of},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const RED_ZONE_SIZE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+pub const POISON_BYTE: u8 = 0x6b;
+
+const LIVE: u64 = 0x_11fe_a110_c8ed_0001;
+const FREED: u64 = 0x_dead_f7ee_d0b1_0002;
+
+/// Lies right before the front red zone. The fields the allocators
+/// overwrite with their free list nodes come first, so that `state` and
+/// `id` survive a free.
+#[repr(C)]
+struct Header {
+    size: usize,
+    align: usize,
+    id: u64,
+    state: u64,
+}
+
+/// Numbers allocations, to tell them apart in reports.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Layout of the whole block and offset of the object in it.
+fn wrapped(layout: Layout) -> (Layout, usize) {
+    let align = layout.align().max(align_of::<Header>());
+    let offset = align_up(size_of::<Header>() + RED_ZONE_SIZE, align);
+    let size = offset + layout.size() + RED_ZONE_SIZE;
+    (Layout::from_size_align(size, align).unwrap(), offset)
+}
+
+unsafe fn header(object: *mut u8) -> *mut Header {
+    object.sub(RED_ZONE_SIZE + size_of::<Header>()) as *mut Header
+}
+
+/// Allocates `layout` with `alloc_block`, surrounded by red zones.
+pub unsafe fn alloc(layout: Layout, alloc_block: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let (block_layout, offset) = wrapped(layout);
+    let block = alloc_block(block_layout);
+    if block.is_null() {
+        return block;
+    }
+    let object = block.add(offset);
+    header(object).write(Header {
+        size: layout.size(),
+        align: layout.align(),
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        state: LIVE,
+    });
+    object
+        .sub(RED_ZONE_SIZE)
+        .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+    object
+        .add(layout.size())
+        .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+    object
+}
+
+/// Checks the allocation at `object`, poisons it and frees it with
+/// `dealloc_block`. Panics on any sign of corruption.
+pub unsafe fn dealloc(
+    object: *mut u8,
+    layout: Layout,
+    dealloc_block: impl FnOnce(*mut u8, Layout),
+) {
+    let header = &mut *header(object);
+    match header.state {
+        LIVE => {}
+        FREED => panic!(
+            "heap-debug: double free of allocation #{} at {:p} ({:?})",
+            header.id, object, layout
+        ),
+        _ => panic!(
+            "heap-debug: free of {:p} ({:?}), which is no allocation or has a corrupted header",
+            object, layout
+        ),
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!(
+            "heap-debug: allocation #{} at {:p} has size {} and align {}, but is freed with {:?}",
+            header.id, object, header.size, header.align, layout
+        );
+    }
+    let front = object.sub(RED_ZONE_SIZE);
+    let back = object.add(layout.size());
+    for (name, zone) in [("front", front), ("back", back)].iter() {
+        if let Some(offset) = (0..RED_ZONE_SIZE).find(|&i| zone.add(i).read() != RED_ZONE_BYTE) {
+            panic!(
+                "heap-debug: {} red zone of allocation #{} at {:p} ({:?}) overwritten at byte {}",
+                name, header.id, object, layout, offset
+            );
+        }
+    }
+
+    header.state = FREED;
+    object.write_bytes(POISON_BYTE, layout.size());
+    let (block_layout, offset) = wrapped(layout);
+    dealloc_block(object.sub(offset), block_layout);
+}
+
+fn align_up(size: usize, align: usize) -> usize {
+    (size + align - 1) & !(align - 1)
+}
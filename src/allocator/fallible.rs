@@ -0,0 +1,158 @@
+//! Allocation that reports failure to the caller instead of ending up in
+//! the `alloc_error_handler`.
+
+use super::{linked_list::LinkedListAllocator, Backend};
+use alloc::{
+    alloc::{alloc, dealloc},
+    boxed::Box,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    alloc::Layout,
+    fmt, mem,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The heap has no room for the layout, even after growing
+    OutOfMemory(Layout),
+    /// The requested size does not fit into a `Layout`
+    CapacityOverflow,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::OutOfMemory(layout) => write!(
+                f,
+                "out of memory allocating {} bytes aligned to {}",
+                layout.size(),
+                layout.align()
+            ),
+            AllocError::CapacityOverflow => write!(f, "capacity overflow"),
+        }
+    }
+}
+
+/// Like `Box::new`, but returns an error if the heap is exhausted.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        // does not allocate
+        return Ok(Box::new(value));
+    }
+    let ptr = unsafe { alloc(layout) } as *mut T;
+    if ptr.is_null() {
+        return Err(AllocError::OutOfMemory(layout));
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Like `Arc::new`, but returns an error if the heap is exhausted.
+pub fn try_arc<T>(value: T) -> Result<Arc<T>, AllocError> {
+    Arc::try_new(value).map_err(|_| {
+        // the reference counts come first
+        Layout::new::<[usize; 2]>()
+            .extend(Layout::new::<T>())
+            .map_or(AllocError::CapacityOverflow, |(layout, _)| {
+                AllocError::OutOfMemory(layout.pad_to_align())
+            })
+    })
+}
+
+/// Like `Vec::with_capacity`, but returns an error if the heap is
+/// exhausted.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let layout = Layout::array::<T>(capacity).map_err(|_| AllocError::CapacityOverflow)?;
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity)
+        .map_err(|_| AllocError::OutOfMemory(layout))?;
+    Ok(vec)
+}
+
+/// Bytes currently held by reservations.
+static RESERVED: AtomicUsize = AtomicUsize::new(0);
+
+/// Heap memory a subsystem sets aside while memory is plentiful, so that
+/// it can still make progress once the heap is exhausted.
+///
+/// The reservation is an emergency pool of its own: only its owner
+/// allocates from it, with `allocate` and `deallocate`, so no other
+/// allocation can take the memory. The owner typically falls back to the
+/// pool when an allocation from the heap fails. `release`, or dropping the
+/// reservation, returns the memory to the heap.
+pub struct Reservation {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    pool: LinkedListAllocator,
+}
+
+// the pool points into the reserved memory, which only the owner touches
+unsafe impl Send for Reservation {}
+unsafe impl Sync for Reservation {}
+
+/// Takes `size` bytes off the heap for an emergency pool.
+pub fn reserve(size: usize) -> Result<Reservation, AllocError> {
+    // the pool keeps a free list node in its memory
+    let size = size.max(2 * mem::size_of::<usize>());
+    let layout = Layout::from_size_align(size, 16).map_err(|_| AllocError::CapacityOverflow)?;
+    let ptr = NonNull::new(unsafe { alloc(layout) }).ok_or(AllocError::OutOfMemory(layout))?;
+    let mut pool = LinkedListAllocator::new();
+    unsafe { pool.init(ptr.as_ptr() as usize, layout.size()) };
+    RESERVED.fetch_add(layout.size(), Ordering::SeqCst);
+    Ok(Reservation { ptr, layout, pool })
+}
+
+/// Bytes the heap has lost to reservations.
+pub fn reserved() -> usize {
+    RESERVED.load(Ordering::SeqCst)
+}
+
+impl Reservation {
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Bytes of the pool that are allocated.
+    pub fn used(&self) -> usize {
+        self.pool.used()
+    }
+
+    /// Allocates `layout` from the pool, never from the heap.
+    pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        NonNull::new(self.pool.allocate(layout)).ok_or(AllocError::OutOfMemory(layout))
+    }
+
+    /// Frees an allocation of the pool.
+    ///
+    /// This function is unsafe because `ptr` must come from `allocate` of
+    /// this reservation, with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.pool.deallocate(ptr.as_ptr(), layout);
+    }
+
+    /// Returns the memory to the heap.
+    pub fn release(self) {
+        // done by `drop`
+    }
+}
+
+impl Drop for Reservation {
+    /// Panics if allocations from the pool are still alive, as they would
+    /// be left pointing into freed heap memory.
+    fn drop(&mut self) {
+        assert_eq!(
+            0,
+            self.pool.used(),
+            "reservation released while its pool is in use"
+        );
+        RESERVED.fetch_sub(self.layout.size(), Ordering::SeqCst);
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
@@ -1,14 +1,17 @@
-#![allow(dead_code)]
+use super::Backend;
 use core::{
-    alloc::{GlobalAlloc, Layout},
+    alloc::Layout,
     mem::{align_of, size_of},
     ptr::NonNull,
 };
 
 use linked_list_allocator::Heap;
-use spin::Mutex;
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Freed blocks beyond this many bytes per list go straight back to the
+/// fallback heap.
+pub const MAX_CACHED_BYTES: usize = 64 * 1024;
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -16,55 +19,107 @@ struct ListNode {
 
 pub struct FixedSizedBlockAllocator {
     linked_lists: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    /// Length of each list
+    cached: [usize; BLOCK_SIZES.len()],
     fallback_allocator: Heap,
 }
 
-pub struct Locked<T> {
-    item: Mutex<T>,
+/// Index of the size class for `layout`, if it is small enough for one.
+pub fn list_index(layout: &Layout) -> Option<usize> {
+    let block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| block_size <= s)
 }
 
-impl<T> Locked<T> {
-    pub const fn new(data: T) -> Locked<T> {
-        Locked {
-            item: Mutex::new(data),
+impl FixedSizedBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizedBlockAllocator {
+            linked_lists: [EMPTY; BLOCK_SIZES.len()],
+            cached: [0; BLOCK_SIZES.len()],
+            fallback_allocator: Heap::empty(),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<T> {
-        self.item.lock()
+    /// Allocates from the fallback heap, emptying the free lists into it
+    /// first if it is too full.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        use core::ptr;
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        if self.reclaim() == 0 {
+            return ptr::null_mut();
+        }
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    /// Gives all cached blocks back to the fallback heap, where they can
+    /// merge into larger holes again. Returns how many there were.
+    pub fn reclaim(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for index in 0..BLOCK_SIZES.len() {
+            while let Some(node) = self.linked_lists[index].take() {
+                self.linked_lists[index] = node.next.take();
+                let ptr = NonNull::from(node).cast();
+                unsafe { self.fallback_allocator.deallocate(ptr, block_layout(index)) };
+                reclaimed += 1;
+            }
+            self.cached[index] = 0;
+        }
+        reclaimed
     }
 }
 
-unsafe impl GlobalAlloc for Locked<FixedSizedBlockAllocator> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut allocator = self.lock();
+/// Layout of the blocks of size class `index`, as they are allocated from
+/// the fallback heap.
+fn block_layout(index: usize) -> Layout {
+    let block_size = BLOCK_SIZES[index];
+    let block_align = block_size;
+    Layout::from_size_align(block_size, block_align).unwrap()
+}
+
+impl Backend for FixedSizedBlockAllocator {
+    const NAME: &'static str = "fixed-size-block";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
         match list_index(&layout) {
             // found a list - we can use it for allocation
-            Some(index) => match allocator.linked_lists[index].take() {
+            Some(index) => match self.linked_lists[index].take() {
                 // use memory from list
                 Some(node) => {
-                    allocator.linked_lists[index] = node.next.take();
+                    self.linked_lists[index] = node.next.take();
+                    self.cached[index] -= 1;
                     node as *mut ListNode as *mut u8
                 }
                 // allocate memory as the list is not created yet
-                None => {
-                    let block_size = BLOCK_SIZES[index];
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
+                None => self.fallback_alloc(block_layout(index)),
             },
             // list not found - use fall back
-            None => allocator.fallback_alloc(layout),
+            None => self.fallback_alloc(layout),
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let mut allocator = self.lock();
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
         match list_index(&layout) {
+            // the list is long enough, so the block goes back to the heap
+            Some(index) if (self.cached[index] + 1) * BLOCK_SIZES[index] > MAX_CACHED_BYTES => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback_allocator.deallocate(ptr, block_layout(index))
+            }
             Some(index) => {
                 let new_node = ListNode {
-                    next: allocator.linked_lists[index].take(),
+                    next: self.linked_lists[index].take(),
                 };
                 let block_size = BLOCK_SIZES[index];
                 assert!(size_of::<ListNode>() <= block_size);
@@ -72,39 +127,36 @@ unsafe impl GlobalAlloc for Locked<FixedSizedBlockAllocator> {
 
                 let node_ptr = ptr as *mut ListNode;
                 node_ptr.write(new_node);
-                allocator.linked_lists[index] = Option::Some(&mut *node_ptr);
+                self.linked_lists[index] = Option::Some(&mut *node_ptr);
+                self.cached[index] += 1;
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout)
+                self.fallback_allocator.deallocate(ptr, layout)
             }
         }
     }
-}
 
-fn list_index(layout: &Layout) -> Option<usize> {
-    let block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| block_size <= s)
-}
+    /// Blocks already have the full size of their class.
+    fn realloc_in_place(&mut self, _ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => new_layout,
+            Err(_) => return false,
+        };
+        let index = list_index(&layout);
+        index.is_some() && index == list_index(&new_layout)
+    }
 
-impl FixedSizedBlockAllocator {
-    pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
-        FixedSizedBlockAllocator {
-            linked_lists: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: Heap::empty(),
-        }
+    fn top(&self) -> usize {
+        self.fallback_allocator.top()
     }
 
-    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        use core::ptr;
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
-        }
+    /// Bytes handed out by the fallback heap, including cached blocks.
+    fn used(&self) -> usize {
+        self.fallback_allocator.used()
     }
 
-    pub unsafe fn init(&mut self, heap_bottom: usize, heap_size: usize) {
-        self.fallback_allocator.init(heap_bottom, heap_size);
+    fn cached(&self, class: usize) -> usize {
+        self.cached[class]
     }
 }
@@ -0,0 +1,48 @@
+use super::Backend;
+use core::{alloc::Layout, ptr, ptr::NonNull};
+use linked_list_allocator::Heap;
+
+/// First fit allocation from a list of free holes, straight from
+/// `linked_list_allocator`.
+pub struct LinkedListAllocator {
+    heap: Heap,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            heap: Heap::empty(),
+        }
+    }
+}
+
+impl Backend for LinkedListAllocator {
+    const NAME: &'static str = "linked-list";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap.extend(by);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match self.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.heap.deallocate(NonNull::new(ptr).unwrap(), layout);
+    }
+
+    fn top(&self) -> usize {
+        self.heap.top()
+    }
+
+    fn used(&self) -> usize {
+        self.heap.used()
+    }
+}
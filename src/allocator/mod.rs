@@ -1,62 +1,365 @@
+pub mod buddy;
+pub mod bump;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod fallible;
 pub mod fixed_size_block;
+pub mod linked_list;
+pub mod slab;
+pub mod stats;
 
-use core::{alloc::GlobalAlloc, ptr::null_mut};
+use crate::memory::{
+    self,
+    vma::{VmaError, VmaManager, KERNEL_VMA_END, KERNEL_VMA_START},
+    BootInfoFrameAllocator,
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::Mutex;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, OffsetPageTable, PageSize, PageTableFlags, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
-use fixed_size_block::FixedSizedBlockAllocator;
-use fixed_size_block::Locked;
+pub use fallible::{
+    reserve, reserved, try_arc, try_box, try_vec_with_capacity, AllocError, Reservation,
+};
+pub use stats::{AllocatorStats, SizeClassStats};
+
+/// A heap allocator for one contiguous range that can grow at its end.
+///
+/// The global allocator is one of these, picked by cargo feature.
+pub trait Backend {
+    /// Name for reports.
+    const NAME: &'static str;
+
+    /// Hands over the `heap_size` bytes at `heap_start`. Called once.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Adds the `by` bytes right after `top`.
+    unsafe fn extend(&mut self, by: usize);
+
+    /// Size and alignment of the largest allocation the backend can serve,
+    /// no matter how far the heap grows.
+    fn max_size(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Returns a null pointer if there is no room left.
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+    /// Resizes the allocation at `ptr` to `new_size` bytes without moving
+    /// it, if that is possible; otherwise `realloc` has to copy.
+    fn realloc_in_place(&mut self, _ptr: *mut u8, _layout: Layout, _new_size: usize) -> bool {
+        false
+    }
+
+    /// End of the heap.
+    fn top(&self) -> usize;
+
+    /// Bytes of the heap that are not available for allocations.
+    fn used(&self) -> usize;
+
+    /// Freed blocks of size class `class` that are kept for reuse.
+    fn cached(&self, _class: usize) -> usize {
+        0
+    }
+}
+
+const _: () = assert!(
+    cfg!(feature = "bump-allocator") as u8
+        + cfg!(feature = "linked-list-allocator") as u8
+        + cfg!(feature = "fixed-size-block-allocator") as u8
+        + cfg!(feature = "buddy-allocator") as u8
+        == 1,
+    "exactly one allocator backend feature has to be enabled"
+);
+
+#[cfg(feature = "bump-allocator")]
+type SelectedBackend = bump::BumpAllocator;
+#[cfg(feature = "linked-list-allocator")]
+type SelectedBackend = linked_list::LinkedListAllocator;
+#[cfg(feature = "fixed-size-block-allocator")]
+type SelectedBackend = fixed_size_block::FixedSizedBlockAllocator;
+#[cfg(feature = "buddy-allocator")]
+type SelectedBackend = buddy::BuddyAllocator;
 
-// #[global_allocator]
-// static ALLOCATOR: Dummy = Dummy;
+/// Name of the backend of the global allocator.
+pub const BACKEND: &str = SelectedBackend::NAME;
 
-pub struct Dummy;
+pub struct Locked<T> {
+    item: Mutex<T>,
+}
 
-unsafe impl GlobalAlloc for Dummy {
-    unsafe fn alloc(&self, _layout: core::alloc::Layout) -> *mut u8 {
-        null_mut()
+impl<T> Locked<T> {
+    pub const fn new(data: T) -> Locked<T> {
+        Locked {
+            item: Mutex::new(data),
+        }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
-        panic!("dealloc should naver be called!")
+    pub fn lock(&self) -> spin::MutexGuard<T> {
+        self.item.lock()
+    }
+}
+
+/// Puts a `Backend` in charge of the heap, growing the heap when the
+/// backend runs out of room and keeping statistics.
+pub struct Allocator<B> {
+    backend: B,
+    peak_used: usize,
+    stats: AllocatorStats,
+}
+
+impl<B: Backend> Allocator<B> {
+    pub const fn new(backend: B) -> Self {
+        Allocator {
+            backend,
+            peak_used: 0,
+            stats: AllocatorStats::new(),
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if layout.size().max(layout.align()) > self.backend.max_size() {
+            // growing the heap would not help
+            return core::ptr::null_mut();
+        }
+        let mut ptr = self.backend.allocate(layout);
+        if ptr.is_null() {
+            // enough for the allocation even if the top of the heap is in
+            // use and the new space has to be aligned
+            let min_size = layout.size() + layout.align();
+            let grown = grow_heap(self.backend.top(), min_size);
+            if grown > 0 {
+                unsafe { self.backend.extend(grown) };
+                ptr = self.backend.allocate(layout);
+            }
+        }
+        if !ptr.is_null() {
+            self.stats.record_alloc(&layout);
+            self.peak_used = self.peak_used.max(self.backend.used());
+        }
+        ptr
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.stats.record_dealloc(&layout);
+        self.backend.deallocate(ptr, layout);
+    }
+
+    // heap-debug needs to move the back red zone, so it always copies
+    #[cfg(not(feature = "heap-debug"))]
+    fn realloc_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        if !self.backend.realloc_in_place(ptr, layout, new_size) {
+            return false;
+        }
+        // the new layout is valid, or the backend would have refused
+        let new_layout = Layout::from_size_align(new_size, layout.align()).unwrap();
+        self.stats.record_dealloc(&layout);
+        self.stats.record_alloc(&new_layout);
+        true
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        let mut stats = self.stats;
+        for (index, class) in stats.classes.iter_mut().enumerate() {
+            class.cached = self.backend.cached(index);
+        }
+        stats
+    }
+}
+
+impl<B> Locked<Allocator<B>> {
+    /// Runs `f` with the allocator locked and interrupts disabled, so that
+    /// no thread is ever preempted while it holds the lock. Otherwise the
+    /// scheduler or an interrupt handler that allocates could spin on the
+    /// lock forever.
+    fn with<R>(&self, f: impl FnOnce(&mut Allocator<B>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.lock()))
+    }
+}
+
+unsafe impl<B: Backend> GlobalAlloc for Locked<Allocator<B>> {
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with(|allocator| allocator.allocate(layout))
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with(|allocator| allocator.deallocate(ptr, layout))
+    }
+
+    /// Stays in place if the backend can do that, and copies otherwise.
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.with(|allocator| allocator.realloc_in_place(ptr, layout, new_size)) {
+            return ptr;
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        debug::alloc(layout, |layout| {
+            self.with(|allocator| allocator.allocate(layout))
+        })
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        debug::dealloc(ptr, layout, |ptr, layout| {
+            self.with(|allocator| allocator.deallocate(ptr, layout))
+        })
     }
 }
 
 // heap
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Start of the heap, aligned to 2 MiB so that it is mapped with huge pages.
+///
+/// The heap has to exist before the `VmaManager`, which keeps its areas on
+/// the heap, so its address cannot come from `VmaManager::reserve`. It is
+/// fixed instead, outside of the range that `reserve` hands out, and
+/// registered with `reserve_heap` once the manager exists.
+pub const HEAP_START: usize = 0x_4444_4440_0000;
+/// Size of the heap mapped by `init_heap`: a single huge page, as any
+/// smaller heap would have to be mapped with 4 KiB pages.
+pub const HEAP_SIZE: usize = Size2MiB::SIZE as usize;
+/// Size of the virtual range reserved for the heap, the hard upper limit.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+/// The heap grows in multiples of this, so that it can use huge pages.
+const HEAP_GROWTH: usize = 2 * 1024 * 1024;
+
+const _: () = assert!(
+    (HEAP_START + HEAP_MAX_SIZE) as u64 <= KERNEL_VMA_START || HEAP_START as u64 >= KERNEL_VMA_END,
+    "the heap overlaps the range of VmaManager::reserve"
+);
+
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// The size the heap may grow to, see `set_heap_limit`.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizedBlockAllocator> = Locked::new(FixedSizedBlockAllocator::new());
+static ALLOCATOR: Locked<Allocator<SelectedBackend>> =
+    Locked::new(Allocator::new(SelectedBackend::new()));
 
+/// Maps the first `HEAP_SIZE` bytes of the heap.
+///
+/// The heap grows on demand once the global frame allocator is installed
+/// (see `memory::init_global_frame_allocator`); until then it is limited
+/// to `HEAP_SIZE`.
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page: Page<Size4KiB> = Page::containing_address(heap_start);
-        let heap_end_page: Page<Size4KiB> = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+    memory::map_allocated_range(
+        mapper,
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_SIZE as u64,
+        HEAP_FLAGS,
+        frame_allocator,
+    )?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.with(|allocator| allocator.backend.init(HEAP_START, HEAP_SIZE));
     }
 
     Ok(())
 }
+
+/// Registers the heap's whole virtual range with the VMA manager, which
+/// needs the heap itself and so can only be created after `init_heap`.
+pub fn reserve_heap(vmas: &mut VmaManager) -> Result<(), VmaError> {
+    vmas.reserve_fixed(
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_MAX_SIZE as u64,
+        "heap",
+    )
+}
+
+/// Lets the heap grow to at most `limit` bytes, rounded down to the growth
+/// granularity and capped at `HEAP_MAX_SIZE`. A heap that is already
+/// larger keeps its size.
+pub fn set_heap_limit(limit: usize) {
+    let limit = limit.min(HEAP_MAX_SIZE) / HEAP_GROWTH * HEAP_GROWTH;
+    HEAP_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapInfo {
+    /// Bytes currently mapped
+    pub size: usize,
+    /// Bytes the heap may grow to
+    pub limit: usize,
+    /// Bytes not available for allocations, see `Backend::used`
+    pub used: usize,
+    /// The highest `used` so far
+    pub peak_used: usize,
+}
+
+pub fn heap_info() -> HeapInfo {
+    ALLOCATOR.with(|allocator| HeapInfo {
+        size: allocator.backend.top().saturating_sub(HEAP_START),
+        limit: HEAP_LIMIT.load(Ordering::SeqCst),
+        used: allocator.backend.used(),
+        peak_used: allocator.peak_used,
+    })
+}
+
+/// A snapshot of the global allocator's counters.
+pub fn stats() -> AllocatorStats {
+    ALLOCATOR.with(|allocator| allocator.stats())
+}
+
+/// Maps at least `min_size` more bytes at `heap_top`, the current end of
+/// the heap, and returns how many bytes it mapped.
+///
+/// Called by the allocator with its lock held, so nothing here may
+/// allocate on the heap. Returns 0 if the limit is reached or the global
+/// frame allocator is not installed or out of frames.
+fn grow_heap(heap_top: usize, min_size: usize) -> usize {
+    let size = heap_top - HEAP_START;
+    let limit = HEAP_LIMIT.load(Ordering::SeqCst);
+    let growth = align_up(min_size, HEAP_GROWTH);
+    if size + growth > limit {
+        return 0;
+    }
+
+    let mapped = memory::try_with_frame_allocator(|frame_allocator| {
+        // the frame allocator lock serializes us with other users of the
+        // kernel page table that go through the global frame allocator
+        let mut mapper = unsafe { memory::kernel_mapper() };
+        let start = VirtAddr::new(heap_top as u64);
+        let (mapped, _) = memory::map_allocated_prefix(
+            &mut mapper,
+            start,
+            growth as u64,
+            HEAP_FLAGS,
+            frame_allocator,
+        );
+        mapped as usize
+    });
+    mapped.unwrap_or(0)
+}
+
+fn align_up(size: usize, align: usize) -> usize {
+    (size + align - 1) & !(align - 1)
+}
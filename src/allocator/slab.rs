@@ -0,0 +1,280 @@
+use crate::memory::{self, GlobalFrameAllocator};
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+const SLAB_SIZE: usize = 4096;
+
+/// Sits at the start of every slab page.
+struct SlabHeader {
+    /// Neighbours in the list of partially used slabs; full slabs are in
+    /// no list at all.
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free: *mut FreeSlot,
+    in_use: usize,
+}
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+struct Slabs {
+    /// Slabs with at least one free slot
+    partial: *mut SlabHeader,
+    slabs: usize,
+    in_use: usize,
+    allocations: usize,
+    frees: usize,
+}
+
+// the slabs are only reachable through the cache's lock
+unsafe impl Send for Slabs {}
+
+/// Counters of a `SlabCache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    /// Pages currently held by the cache
+    pub slabs: usize,
+    pub in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+/// A cache of objects of type `T`, carved out of whole pages.
+///
+/// Every slab is a single frame from `GlobalFrameAllocator`, accessed
+/// through the physical memory window, so caches can only be used after
+/// `memory::init_global_frame_allocator`. A slab goes back to the frame
+/// allocator as soon as its last object is freed. Objects must fit into a
+/// page together with the slab header.
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: fn() -> T,
+    slabs: Mutex<Slabs>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = max(align_of::<T>(), align_of::<FreeSlot>());
+    const SLOT_SIZE: usize = align_up(max(size_of::<T>(), size_of::<FreeSlot>()), Self::SLOT_ALIGN);
+    const FIRST_SLOT: usize = align_up(size_of::<SlabHeader>(), Self::SLOT_ALIGN);
+    pub const OBJECTS_PER_SLAB: usize = if Self::FIRST_SLOT < SLAB_SIZE {
+        (SLAB_SIZE - Self::FIRST_SLOT) / Self::SLOT_SIZE
+    } else {
+        0
+    };
+
+    /// Creates an empty cache whose `alloc` builds objects with
+    /// `constructor`.
+    ///
+    /// Panics if `T` does not fit into a slab.
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        assert!(Self::OBJECTS_PER_SLAB > 0, "object too large for a slab");
+        SlabCache {
+            name,
+            constructor,
+            slabs: Mutex::new(Slabs {
+                partial: ptr::null_mut(),
+                slabs: 0,
+                in_use: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Allocates an object built by the cache's constructor. Returns `None`
+    /// if a new slab is needed and no frame is left.
+    pub fn alloc(&'static self) -> Option<SlabBox<T>> {
+        self.alloc_with((self.constructor)())
+    }
+
+    /// Allocates an object holding `value`.
+    pub fn alloc_with(&'static self, value: T) -> Option<SlabBox<T>> {
+        let slot = self.alloc_slot()?;
+        unsafe { slot.as_ptr().write(value) };
+        Some(SlabBox {
+            ptr: slot,
+            cache: self,
+        })
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let slabs = self.slabs.lock();
+        SlabStats {
+            name: self.name,
+            object_size: size_of::<T>(),
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: slabs.slabs,
+            in_use: slabs.in_use,
+            allocations: slabs.allocations,
+            frees: slabs.frees,
+        }
+    }
+
+    fn alloc_slot(&self) -> Option<NonNull<T>> {
+        let mut slabs = self.slabs.lock();
+        if slabs.partial.is_null() {
+            let slab = Self::new_slab()?;
+            slabs.partial = slab;
+            slabs.slabs += 1;
+        }
+        unsafe {
+            let slab = slabs.partial;
+            let slot = (*slab).free;
+            (*slab).free = (*slot).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                unlink(&mut slabs, slab);
+            }
+            slabs.in_use += 1;
+            slabs.allocations += 1;
+            NonNull::new(slot as *mut T)
+        }
+    }
+
+    /// Returns the slot of an object that was dropped already.
+    unsafe fn free_slot(&self, ptr: NonNull<T>) {
+        let mut slabs = self.slabs.lock();
+        let slab = (ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let was_full = (*slab).free.is_null();
+
+        let slot = ptr.as_ptr() as *mut FreeSlot;
+        slot.write(FreeSlot { next: (*slab).free });
+        (*slab).free = slot;
+        (*slab).in_use -= 1;
+        slabs.in_use -= 1;
+        slabs.frees += 1;
+
+        if (*slab).in_use == 0 {
+            if !was_full {
+                unlink(&mut slabs, slab);
+            }
+            slabs.slabs -= 1;
+            let phys = slab as u64 - memory::physical_memory_offset().as_u64();
+            GlobalFrameAllocator
+                .deallocate_frame(PhysFrame::containing_address(PhysAddr::new(phys)));
+        } else if was_full {
+            push(&mut slabs, slab);
+        }
+    }
+
+    /// Takes a frame and threads all its slots onto the free list.
+    fn new_slab() -> Option<*mut SlabHeader> {
+        let frame = GlobalFrameAllocator.allocate_frame()?;
+        let start = memory::physical_memory_offset() + frame.start_address().as_u64();
+        let slab: *mut SlabHeader = start.as_mut_ptr();
+
+        let mut free = ptr::null_mut();
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let slot: *mut FreeSlot =
+                (start + (Self::FIRST_SLOT + index * Self::SLOT_SIZE) as u64).as_mut_ptr();
+            unsafe { slot.write(FreeSlot { next: free }) };
+            free = slot;
+        }
+        unsafe {
+            slab.write(SlabHeader {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free,
+                in_use: 0,
+            })
+        };
+        Some(slab)
+    }
+}
+
+/// Adds `slab` to the front of the partial list.
+unsafe fn push(slabs: &mut Slabs, slab: *mut SlabHeader) {
+    (*slab).prev = ptr::null_mut();
+    (*slab).next = slabs.partial;
+    if !slabs.partial.is_null() {
+        (*slabs.partial).prev = slab;
+    }
+    slabs.partial = slab;
+}
+
+/// Removes `slab` from the partial list.
+unsafe fn unlink(slabs: &mut Slabs, slab: *mut SlabHeader) {
+    let (prev, next) = ((*slab).prev, (*slab).next);
+    if prev.is_null() {
+        slabs.partial = next;
+    } else {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+    (*slab).prev = ptr::null_mut();
+    (*slab).next = ptr::null_mut();
+}
+
+/// An object owned by a `SlabCache`, returned to it on drop.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    /// Address of the object, which lives in the physical memory window.
+    pub fn addr(this: &Self) -> VirtAddr {
+        VirtAddr::from_ptr(this.ptr.as_ptr())
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free_slot(self.ptr);
+        }
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn align_up(size: usize, align: usize) -> usize {
+    (size + align - 1) & !(align - 1)
+}
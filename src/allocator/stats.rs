@@ -0,0 +1,108 @@
+use super::fixed_size_block::{list_index, BLOCK_SIZES};
+use core::{alloc::Layout, fmt};
+
+/// Counters of one size class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// Freed blocks waiting in the free list
+    pub cached: usize,
+}
+
+/// A snapshot of the allocator's counters.
+///
+/// Allocations are sorted into the size classes of the fixed-size block
+/// allocator whatever the backend; only that one caches blocks, though.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub classes: [SizeClassStats; BLOCK_SIZES.len()],
+    /// Allocations too large for any size class
+    pub fallback_allocations: usize,
+    pub fallback_frees: usize,
+    /// Bytes of such allocations currently in use
+    pub fallback_bytes: usize,
+    /// The highest `fallback_bytes` so far
+    pub fallback_peak_bytes: usize,
+}
+
+impl AllocatorStats {
+    pub(super) const fn new() -> Self {
+        const EMPTY: SizeClassStats = SizeClassStats {
+            block_size: 0,
+            allocations: 0,
+            frees: 0,
+            cached: 0,
+        };
+        let mut classes = [EMPTY; BLOCK_SIZES.len()];
+        let mut index = 0;
+        while index < BLOCK_SIZES.len() {
+            classes[index].block_size = BLOCK_SIZES[index];
+            index += 1;
+        }
+        AllocatorStats {
+            classes,
+            fallback_allocations: 0,
+            fallback_frees: 0,
+            fallback_bytes: 0,
+            fallback_peak_bytes: 0,
+        }
+    }
+
+    /// Allocations that were not freed yet, over all size classes and the
+    /// fallback allocator.
+    pub fn net_allocations(&self) -> isize {
+        let allocations = self
+            .classes
+            .iter()
+            .map(|class| class.allocations)
+            .sum::<usize>()
+            + self.fallback_allocations;
+        let frees =
+            self.classes.iter().map(|class| class.frees).sum::<usize>() + self.fallback_frees;
+        allocations.wrapping_sub(frees) as isize
+    }
+
+    pub(super) fn record_alloc(&mut self, layout: &Layout) {
+        match list_index(layout) {
+            Some(index) => self.classes[index].allocations += 1,
+            None => {
+                self.fallback_allocations += 1;
+                self.fallback_bytes += layout.size();
+                self.fallback_peak_bytes = self.fallback_peak_bytes.max(self.fallback_bytes);
+            }
+        }
+    }
+
+    pub(super) fn record_dealloc(&mut self, layout: &Layout) {
+        match list_index(layout) {
+            Some(index) => self.classes[index].frees += 1,
+            None => {
+                self.fallback_frees += 1;
+                self.fallback_bytes -= layout.size();
+            }
+        }
+    }
+}
+
+/// One line per size class and one for the fallback allocator.
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for class in self.classes.iter() {
+            writeln!(
+                f,
+                "{:>5} B: {} allocated, {} freed, {} cached",
+                class.block_size, class.allocations, class.frees, class.cached
+            )?;
+        }
+        write!(
+            f,
+            "fallback: {} allocated, {} freed, {} bytes in use, {} bytes peak",
+            self.fallback_allocations,
+            self.fallback_frees,
+            self.fallback_bytes,
+            self.fallback_peak_bytes
+        )
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -5,47 +7,106 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_STACK_TABLE_INDEX: u16 = 0;
 
+/// The bootstrap processor's TSS. Its privilege stack changes with the
+/// thread that runs in user mode, see `PerCpu::set_privilege_stack_top`.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_STACK_TABLE_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let start_stack = VirtAddr::from_ptr(unsafe { &STACK });
-            let end_stack = start_stack + STACK_SIZE;
-            end_stack
-        };
-        tss
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(unsafe { &*addr_of!(TSS) });
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let cs_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                cs_selector,
-                tss_selector,
-            },
-        )
-    };
-}
-
-struct Selectors {
-    cs_selector: SegmentSelector,
+/// Segment selectors, identical in the GDT of every CPU.
+///
+/// The order of the entries is fixed by `syscall`/`sysret`: kernel data
+/// must follow kernel code, user code must follow user data.
+pub struct Selectors {
+    pub kernel_code_selector: SegmentSelector,
+    pub kernel_data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            kernel_code_selector,
+            kernel_data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.kernel_code_selector);
+        SS::set_reg(gdt.1.kernel_data_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Loads the bootstrap processor's GDT and TSS.
+pub fn init() {
+    let tss = bsp_tss();
     unsafe {
-        CS::set_reg(GDT.1.cs_selector);
-        load_tss(GDT.1.tss_selector);
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_STACK_TABLE_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            let start_stack = VirtAddr::from_ptr(addr_of!(STACK));
+            let end_stack = start_stack + STACK_SIZE;
+            end_stack
+        };
+        // stack for interrupts arriving while the CPU runs in ring 3
+        (*tss).privilege_stack_table[0] = {
+            const STACK_SIZE: usize = 4096 * 4;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            let start_stack = VirtAddr::from_ptr(addr_of!(STACK));
+            start_stack + STACK_SIZE
+        };
     }
+    load(&GDT);
+}
+
+/// The TSS loaded by `init`, for `percpu::init`.
+pub fn bsp_tss() -> *mut TaskStateSegment {
+    // taking the address is only unsafe on older toolchains
+    #[allow(unused_unsafe)]
+    unsafe {
+        addr_of_mut!(TSS)
+    }
+}
+
+/// Builds and loads a GDT and TSS for an application processor.
+///
+/// Every CPU needs its own TSS, since the TSS is marked busy on load and
+/// holds the stacks used by the double fault handler and for interrupts
+/// from ring 3. Both tables are leaked, as they must live for as long as
+/// the CPU runs. Returns the TSS, for `percpu::init`.
+pub fn init_ap(
+    double_fault_stack_top: VirtAddr,
+    privilege_stack_top: VirtAddr,
+) -> *mut TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_STACK_TABLE_INDEX as usize] = double_fault_stack_top;
+    tss.privilege_stack_table[0] = privilege_stack_top;
+    let tss = Box::into_raw(Box::new(tss));
+    load(Box::leak(Box::new(new_gdt(unsafe { &*tss }))));
+    tss
 }
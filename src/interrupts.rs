@@ -2,8 +2,12 @@ use crate::cprint;
 use crate::cprintln;
 use crate::gdt;
 use crate::hlt_loop;
+use crate::memory;
+use crate::thread;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
+use x86_64::instructions::segmentation::GS;
 use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
@@ -29,6 +33,12 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let write_protect_fault =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(write_protect_fault) && memory::cow::handle_write_fault(Cr2::read()) {
+        return;
+    }
+
     cprintln!(DarkGray, "CPU EXCEPTION: Page Fault!");
     cprintln!(DarkGray, "Adress accessed: {:?}", Cr2::read());
     cprintln!(DarkGray, "Stack frame: \n {:#?}", stack_frame);
@@ -56,12 +66,52 @@ pub fn init_idt() {
 
 // Hardware interrupts
 
-extern "x86-interrupt" fn timer_intr_handler(_stack_frame: InterruptStackFrame) {
+/// Number of PIT timer interrupts seen since boot (~18.2 per second).
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// the PIT runs at 1193182 Hz and interrupts every 65536 cycles by default
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65_536;
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts milliseconds to timer ticks, rounding up.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let divisor = PIT_DIVISOR * 1000;
+    (ms * PIT_FREQUENCY + divisor - 1) / divisor
+}
+
+/// Halts until at least `n` more timer ticks have elapsed.
+///
+/// Interrupts must be enabled, otherwise this never returns.
+pub fn wait_ticks(n: u64) {
+    let target = ticks() + n;
+    while ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+extern "x86-interrupt" fn timer_intr_handler(stack_frame: InterruptStackFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
     cprint!(LightGreen, ".");
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
     };
+
+    // the scheduler needs the kernel GS base for the per-CPU data; the
+    // threads it switches to expect it too, so this is per CPU, not per
+    // thread
+    let from_user = stack_frame.code_segment & 3 == 3;
+    if from_user {
+        unsafe { GS::swap() };
+    }
+    thread::preempt();
+    if from_user {
+        unsafe { GS::swap() };
+    }
 }
 
 extern "x86-interrupt" fn keyboard_intr_handler(_stack_frame: InterruptStackFrame) {
@@ -5,6 +5,7 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![feature(const_mut_refs)]
 
 extern crate alloc;
@@ -14,10 +15,17 @@ pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod serial;
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod thread;
 pub mod vga_buffer;
 
-use core::{alloc::Layout, panic::PanicInfo};
+use core::{
+    alloc::Layout,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 pub fn init() {
     gdt::init();
@@ -29,13 +37,27 @@ pub trait Testable {
     fn run(&self);
 }
 
+static LEAK_CHECK: AtomicBool = AtomicBool::new(true);
+
+/// Turns off the check that fails every test that does not free all of
+/// its allocations, for test binaries whose tests keep shared state on
+/// the heap.
+pub fn disable_leak_check() {
+    LEAK_CHECK.store(false, Ordering::SeqCst);
+}
+
 impl<T> Testable for T
 where
     T: Fn(),
 {
     fn run(&self) {
         sprint!("Executing {}... ", core::any::type_name::<T>());
+        let before = allocator::stats().net_allocations();
         self();
+        let leaked = allocator::stats().net_allocations() - before;
+        if leaked != 0 && LEAK_CHECK.load(Ordering::SeqCst) {
+            panic!("test leaked {} allocations", leaked);
+        }
         sprintln!("[OK]");
     }
 }
@@ -78,7 +100,14 @@ pub fn hlt_loop() -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
-    panic!("allocation error: {:?}", layout);
+    // the allocator lock is free again, and none of this allocates
+    panic!(
+        "allocation error: {:?}\n{:?}, {} bytes reserved\n{}",
+        layout,
+        allocator::heap_info(),
+        allocator::reserved(),
+        allocator::stats()
+    );
 }
 
 /// Entry point for `cargo test`
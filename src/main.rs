@@ -12,13 +12,16 @@ use alloc::rc::Rc;
 use alloc::vec;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use toy_os::allocator::init_heap;
+use toy_os::allocator::{init_heap, reserve_heap};
 use toy_os::memory;
+use toy_os::memory::vma::VmaManager;
 use toy_os::println;
+use toy_os::smp;
 use toy_os::task::executor::Executor;
 use toy_os::task::keyboard;
 use toy_os::task::simple_executor::SimpleExecutor;
-use toy_os::task::Task;
+use toy_os::task::{Priority, Task};
+use toy_os::thread;
 use x86_64::VirtAddr;
 
 //cprintln
@@ -36,12 +39,33 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
     init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    unsafe { memory::sections::protect_kernel(&mut mapper, &boot_info.memory_map) }
+        .expect("kernel remapping failed");
+    memory::buddy::init_dma_frame_allocator(&mut frame_allocator);
+
+    let mut vmas = VmaManager::new(phys_mem_offset, &boot_info.memory_map);
+    reserve_heap(&mut vmas).expect("heap overlaps a protected region");
+    smp::init(boot_info, &mut vmas, &mut mapper, &mut frame_allocator).expect("smp init failed");
+    memory::address_space::init_kernel_tables(&mut frame_allocator)
+        .expect("kernel tables init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+    thread::init(&mut vmas).expect("thread init failed");
+    println!("{} of {} CPUs online", smp::online_cpus(), smp::cpu_count());
+
+    #[cfg(feature = "dump-page-tables")]
+    {
+        let mappings = unsafe { memory::walker::active_mappings(phys_mem_offset) };
+        memory::walker::dump(&mappings, memory::walker::Output::Serial);
+    }
 
     #[cfg(not(test))]
-    run_executor();
+    {
+        let executor = thread::spawn_thread("executor", run_executor).expect("spawn failed");
+        thread::join(executor);
+    }
 
     #[cfg(not(test))]
     other_calls();
@@ -61,7 +85,11 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 fn run_executor() {
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_key_strokes()));
+    // typing stays responsive however busy the other tasks are
+    executor.spawn(Task::with_priority(
+        keyboard::print_key_strokes(),
+        Priority::High,
+    ));
     executor.run();
 }
 
@@ -120,30 +148,6 @@ fn other_calls() {
 
     // println!("ref count -> {}", Rc::strong_count(&rc_clone));
 
-    // read page table
-
-    // let (level_4_page_table, _) = Cr3::read();
-
-    // cprintln!(
-    //     Pink,
-    //     "Level 4 page table starts at {:?}",
-    //     level_4_page_table.start_address()
-    // );
-
-    // cprintln!(
-    //     LightGray,
-    //     "physical memory offset : {:?}",
-    //     VirtAddr::new(boot_info.physical_memory_offset)
-    // );
-
-    // let l4_table = unsafe { active_level_4_table(phys_mem_offset) };
-
-    // for (i, entry) in l4_table.iter().enumerate() {
-    //     if !entry.is_unused() {
-    //         cprintln!(LightGreen, "L4 Entry {} -> {:?}", i, entry);
-    //     }
-    // }
-
     // new: initialize a mapper
 
     // let addresses = [
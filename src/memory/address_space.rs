@@ -0,0 +1,326 @@
+use super::{
+    cow::{self, COPY_ON_WRITE, OWNED},
+    GlobalFrameAllocator, KERNEL_LEVEL_4_TABLE, USER_SPACE_END, USER_SPACE_START,
+};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, TranslateResult, UnmapError},
+        page_table::{FrameError, PageTableEntry},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Level 4 entries covering user space. All other entries belong to the
+/// kernel and are shared by every address space.
+const USER_ENTRIES: Range<usize> = (USER_SPACE_START >> 39)..(USER_SPACE_END >> 39);
+
+/// Set by `init_kernel_tables`.
+static KERNEL_TABLES_READY: AtomicBool = AtomicBool::new(false);
+
+/// Gives every kernel level 4 entry of the kernel's page table a level 3
+/// table, so that the kernel never changes its level 4 table again and
+/// address spaces can share all of the kernel's mappings by linking to
+/// these tables. Costs one frame per empty entry, about 2 MiB in total.
+///
+/// Must be called once, before the first `AddressSpace::new` and while no
+/// other CPU changes the kernel's page table.
+pub fn init_kernel_tables(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let physical_memory_offset = super::physical_memory_offset();
+    let kernel_table = unsafe { &mut *kernel_level_4_table(physical_memory_offset) };
+    for (index, entry) in kernel_table.iter_mut().enumerate() {
+        if USER_ENTRIES.contains(&index) || !entry.is_unused() {
+            continue;
+        }
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let table: *mut PageTable =
+            (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { (*table).zero() };
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    KERNEL_TABLES_READY.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+fn kernel_level_4_table(physical_memory_offset: VirtAddr) -> *mut PageTable {
+    let addr = KERNEL_LEVEL_4_TABLE.load(Ordering::SeqCst);
+    (physical_memory_offset + addr).as_mut_ptr()
+}
+
+/// A set of page tables with private user space mappings.
+///
+/// Every level 4 entry outside of user space points to the same level 3
+/// table as in the kernel's page table, which `init_kernel_tables` set up
+/// for all of them, so mappings the kernel makes later on are visible in
+/// every address space.
+///
+/// Page tables come from the global frame allocator (see
+/// `init_global_frame_allocator`) and are freed when the address space is
+/// dropped. Frames mapped with `map` still belong to the caller, until
+/// `fork` turns them into shared copy-on-write frames (see `cow`).
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half.
+    ///
+    /// Panics if `init_kernel_tables` was not called.
+    pub fn new(physical_memory_offset: VirtAddr) -> Result<Self, MapToError<Size4KiB>> {
+        assert!(
+            KERNEL_TABLES_READY.load(Ordering::SeqCst),
+            "address_space::init_kernel_tables was not called"
+        );
+        let level_4_frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let mut address_space = AddressSpace {
+            level_4_frame,
+            physical_memory_offset,
+        };
+
+        let kernel_table = unsafe { &*kernel_level_4_table(physical_memory_offset) };
+        let table = address_space.level_4_table();
+        table.zero();
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if !USER_ENTRIES.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(address_space)
+    }
+
+    /// Physical frame of the level 4 table, as loaded into CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Whether this address space is active on the calling CPU.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Makes this the active address space of the calling CPU.
+    ///
+    /// This function is unsafe because the caller must switch away again
+    /// on every CPU that uses the address space before it is dropped; only
+    /// the calling CPU is taken care of by `drop`.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    /// Maps the user space page `page` to `frame`.
+    ///
+    /// Panics if `page` is outside of user space.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// frame is not in use otherwise, unless sharing it is intended.
+    pub unsafe fn map(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert_user_page(page);
+        let active = self.is_active();
+        // tables stay writable, so that copy-on-write pages can become
+        // writable later on
+        let table_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | (flags & PageTableFlags::USER_ACCESSIBLE);
+        let flush = self.mapper().map_to_with_table_flags(
+            page,
+            frame,
+            flags,
+            table_flags,
+            &mut GlobalFrameAllocator,
+        )?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// Removes the mapping of the user space page `page` and returns the
+    /// frame it was mapped to. Page tables are only freed on drop.
+    ///
+    /// Copy-on-write and copied frames belong to the address space and are
+    /// released here, so the caller must not use the returned frame then.
+    pub fn unmap(&mut self, page: Page) -> Result<PhysFrame, UnmapError> {
+        assert_user_page(page);
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        let flags = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => PageTableFlags::empty(),
+        };
+        let (frame, flush) = mapper.unmap(page)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        cow::release_page(frame, flags);
+        Ok(frame)
+    }
+
+    /// Creates a copy of this address space that shares all user pages.
+    ///
+    /// Writable pages become read-only copy-on-write pages in both address
+    /// spaces, and their frames become reference counted and owned by the
+    /// address spaces. Read-only pages are simply shared.
+    pub fn fork(&mut self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut child = AddressSpace::new(self.physical_memory_offset)?;
+        let active = self.is_active();
+        unsafe {
+            self.for_each_user_page::<MapToError<Size4KiB>>(|page, entry| {
+                let frame = PhysFrame::containing_address(entry.addr());
+                let mut flags = entry.flags();
+                let shared = flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE);
+                if shared {
+                    // a copy-on-write page with a single reference is simply
+                    // made writable again, so this is fine if `map` fails
+                    flags = (flags - PageTableFlags::WRITABLE - OWNED) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+                child.map(page, frame, flags)?;
+                if shared {
+                    cow::share(frame);
+                }
+                Ok(())
+            })?;
+        }
+        if active {
+            x86_64::instructions::tlb::flush_all();
+        }
+        Ok(child)
+    }
+
+    /// Translates `addr` through the page tables of this address space.
+    pub fn translate_addr(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// A mapper for this address space's page tables.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let physical_memory_offset = self.physical_memory_offset;
+        unsafe { OffsetPageTable::new(self.level_4_table(), physical_memory_offset) }
+    }
+
+    fn level_4_table(&mut self) -> &mut PageTable {
+        unsafe { &mut *self.table_ptr(self.level_4_frame) }
+    }
+
+    fn table_ptr(&self, frame: PhysFrame) -> *mut PageTable {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    /// Calls `f` for every mapped 4 KiB page in user space, stopping at the
+    /// first error.
+    unsafe fn for_each_user_page<E>(
+        &self,
+        mut f: impl FnMut(Page, &mut PageTableEntry) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let level_4_table = &*self.table_ptr(self.level_4_frame);
+        for p4 in USER_ENTRIES {
+            let level_3_table = match level_4_table[p4].frame() {
+                Ok(frame) => &*self.table_ptr(frame),
+                Err(_) => continue,
+            };
+            for (p3, entry) in level_3_table.iter().enumerate() {
+                let level_2_table = match entry.frame() {
+                    Ok(frame) => &*self.table_ptr(frame),
+                    Err(_) => continue,
+                };
+                for (p2, entry) in level_2_table.iter().enumerate() {
+                    let level_1_table = match entry.frame() {
+                        Ok(frame) => &mut *self.table_ptr(frame),
+                        Err(_) => continue,
+                    };
+                    for (p1, entry) in level_1_table.iter_mut().enumerate() {
+                        if !entry.flags().contains(PageTableFlags::PRESENT) {
+                            continue;
+                        }
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(p4 as u16),
+                            PageTableIndex::new(p3 as u16),
+                            PageTableIndex::new(p2 as u16),
+                            PageTableIndex::new(p1 as u16),
+                        );
+                        f(page, entry)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees the page tables below `table` down to `level` 1, but not
+    /// `table` itself.
+    unsafe fn free_tables(&self, table: &PageTable, level: usize) {
+        for entry in table.iter() {
+            let frame = match entry.frame() {
+                Ok(frame) => frame,
+                // huge pages and empty entries have no tables below them
+                Err(FrameError::HugeFrame) | Err(FrameError::FrameNotPresent) => continue,
+            };
+            if level > 2 {
+                self.free_tables(&*self.table_ptr(frame), level - 1);
+            }
+            GlobalFrameAllocator.deallocate_frame(frame);
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            let kernel_table = PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::SeqCst));
+            let (_, flags) = Cr3::read();
+            unsafe { Cr3::write(PhysFrame::containing_address(kernel_table), flags) };
+        }
+
+        unsafe {
+            self.for_each_user_page::<()>(|_, entry| {
+                let frame = PhysFrame::containing_address(entry.addr());
+                cow::release_page(frame, entry.flags());
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        let table = unsafe { &*self.table_ptr(self.level_4_frame) };
+        for index in USER_ENTRIES {
+            if let Ok(frame) = table[index].frame() {
+                unsafe {
+                    self.free_tables(&*self.table_ptr(frame), 3);
+                    GlobalFrameAllocator.deallocate_frame(frame);
+                }
+            }
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+fn assert_user_page(page: Page) {
+    let addr = page.start_address().as_u64() as usize;
+    assert!(
+        (USER_SPACE_START..USER_SPACE_END).contains(&addr),
+        "{:?} is not in user space",
+        page
+    );
+}
@@ -0,0 +1,276 @@
+use super::BootInfoFrameAllocator;
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{frame::PhysFrameRange, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// Largest block order; a block of order `n` spans `2^n` frames (4 MiB here).
+pub const MAX_ORDER: usize = 10;
+
+/// Physical address limit for legacy ISA DMA.
+pub const DMA_LIMIT: u64 = 16 * 1024 * 1024;
+/// Physical address limit for devices with 32-bit DMA addressing.
+pub const DMA32_LIMIT: u64 = 4 * 1024 * 1024 * 1024;
+
+const FRAME_SIZE: u64 = 4096;
+
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Returns the smallest order whose blocks hold `bytes`.
+pub fn order_for_size(bytes: u64) -> usize {
+    let frames = (bytes.max(1) + FRAME_SIZE - 1) / FRAME_SIZE;
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Header written into the first bytes of every free block.
+struct FreeBlock {
+    prev: Option<PhysAddr>,
+    next: Option<PhysAddr>,
+}
+
+/// A buddy system allocator for physically contiguous runs of frames.
+///
+/// Blocks of order `n` are `2^n` frames long and aligned to their size.
+/// Free blocks are kept in one doubly linked list per order, linked
+/// through the blocks themselves via the physical memory window, and
+/// marked in one bitmap per order, so that checking whether a buddy is
+/// free and taking it off its list are constant time. A freed block is
+/// merged with its buddy whenever the buddy is free as well.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    /// Frames can only be added below this address.
+    end: PhysAddr,
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    /// One bit per block of each order, set while it is on its free list.
+    free_bitmaps: [Vec<u64>; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an empty allocator for frames below `end`; they are added
+    /// with `add_frames_from`. Its bitmaps are allocated on the heap.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// complete physical memory is mapped at `physical_memory_offset`.
+    pub unsafe fn new(physical_memory_offset: VirtAddr, end: PhysAddr) -> Self {
+        let end = end.align_down(FRAME_SIZE);
+        let free_bitmaps = core::array::from_fn(|order| {
+            let blocks = (end.as_u64() / block_size(order)) as usize;
+            vec![0; (blocks + 63) / 64]
+        });
+        BuddyFrameAllocator {
+            physical_memory_offset,
+            end,
+            free_lists: [None; MAX_ORDER + 1],
+            free_bitmaps,
+            free_frames: 0,
+        }
+    }
+
+    /// Takes every free frame in `range` below the end of this allocator
+    /// from `frame_allocator` and adds it. Returns the number of frames
+    /// taken.
+    pub fn add_frames_from(
+        &mut self,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        range: Range<PhysAddr>,
+    ) -> usize {
+        let start = range.start.align_up(FRAME_SIZE).as_u64();
+        let end = range.end.min(self.end).align_down(FRAME_SIZE).as_u64();
+        let mut added = 0;
+        let mut run_start = start;
+        for addr in (start..end).step_by(FRAME_SIZE as usize) {
+            let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+            if frame_allocator.claim(frame) {
+                added += 1;
+            } else {
+                self.add_run(run_start, addr);
+                run_start = addr + FRAME_SIZE;
+            }
+        }
+        self.add_run(run_start, end.max(run_start));
+        added
+    }
+
+    /// Frees the frames of `start..end` in the largest blocks their
+    /// alignment allows.
+    fn add_run(&mut self, start: u64, end: u64) {
+        let mut addr = start;
+        while addr < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| addr % block_size(order) == 0 && addr + block_size(order) <= end)
+                .unwrap();
+            self.free_block(PhysAddr::new(addr), order);
+            self.free_frames += 1 << order;
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates `2^order` contiguous frames.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrameRange> {
+        self.allocate_below(order, FRAME_SIZE, PhysAddr::new(u64::MAX))
+    }
+
+    /// Allocates `2^order` contiguous frames that start at a multiple of
+    /// `align` bytes and end at or below `limit`.
+    ///
+    /// Blocks are always aligned to their own size, so `align` only matters
+    /// if it is larger than that. Panics if `align` is not a power of two.
+    pub fn allocate_below(
+        &mut self,
+        order: usize,
+        align: u64,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if order > MAX_ORDER {
+            return None;
+        }
+        let align = align.max(block_size(order));
+        let size = block_size(order);
+
+        // splitting always keeps the lower half, so the result starts at the
+        // start of the block taken from the list
+        let (mut block_order, addr) = (order..=MAX_ORDER).find_map(|o| {
+            self.iter_free(o)
+                .find(|addr| addr.is_aligned(align) && addr.as_u64() + size <= limit.as_u64())
+                .map(|addr| (o, addr))
+        })?;
+
+        self.remove_block(addr, block_order);
+        while block_order > order {
+            block_order -= 1;
+            self.push_block(addr + block_size(block_order), block_order);
+        }
+        self.free_frames -= 1 << order;
+
+        let start = PhysFrame::containing_address(addr);
+        Some(PhysFrame::range(start, start + (1 << order)))
+    }
+
+    /// Returns a block obtained from `allocate` or `allocate_below`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// range was allocated by this allocator and is no longer in use.
+    pub unsafe fn deallocate(&mut self, range: PhysFrameRange) {
+        let frames = range.end - range.start;
+        assert!(frames.is_power_of_two(), "not a buddy block: {:?}", range);
+        let order = frames.trailing_zeros() as usize;
+        self.free_block(range.start.start_address(), order);
+        self.free_frames += frames as usize;
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of free blocks of the given order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.iter_free(order).count()
+    }
+
+    /// Puts a block on a free list, merging it with its buddy as long as
+    /// the buddy is free too.
+    fn free_block(&mut self, mut addr: PhysAddr, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_size(order));
+            if !self.remove_block(buddy, order) {
+                break;
+            }
+            addr = PhysAddr::new(addr.as_u64().min(buddy.as_u64()));
+            order += 1;
+        }
+        self.push_block(addr, order);
+    }
+
+    fn node(&self, addr: PhysAddr) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr.as_u64()).as_mut_ptr()
+    }
+
+    /// Bitmap word and bit of the block of `order` at `addr`.
+    fn bit(&self, addr: PhysAddr, order: usize) -> (usize, u64) {
+        let index = (addr.as_u64() / block_size(order)) as usize;
+        (index / 64, 1 << (index % 64))
+    }
+
+    fn is_free(&self, addr: PhysAddr, order: usize) -> bool {
+        let (word, bit) = self.bit(addr, order);
+        self.free_bitmaps[order]
+            .get(word)
+            .map_or(false, |&bits| bits & bit != 0)
+    }
+
+    fn push_block(&mut self, addr: PhysAddr, order: usize) {
+        let next = self.free_lists[order];
+        if let Some(next) = next {
+            unsafe { (*self.node(next)).prev = Some(addr) };
+        }
+        unsafe { self.node(addr).write(FreeBlock { prev: None, next }) };
+        self.free_lists[order] = Some(addr);
+        let (word, bit) = self.bit(addr, order);
+        self.free_bitmaps[order][word] |= bit;
+    }
+
+    /// Unlinks the block at `addr` from the free list of `order`, if it is
+    /// there.
+    fn remove_block(&mut self, addr: PhysAddr, order: usize) -> bool {
+        if !self.is_free(addr, order) {
+            return false;
+        }
+        let FreeBlock { prev, next } = unsafe { self.node(addr).read() };
+        match prev {
+            Some(prev) => unsafe { (*self.node(prev)).next = next },
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            unsafe { (*self.node(next)).prev = prev };
+        }
+        let (word, bit) = self.bit(addr, order);
+        self.free_bitmaps[order][word] &= !bit;
+        true
+    }
+
+    fn iter_free(&self, order: usize) -> impl Iterator<Item = PhysAddr> + '_ {
+        let mut current = self.free_lists[order];
+        core::iter::from_fn(move || {
+            let block = current?;
+            current = unsafe { (*self.node(block)).next };
+            Some(block)
+        })
+    }
+}
+
+static DMA_FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+/// Moves all free frames below `DMA_LIMIT` from `frame_allocator` to the
+/// allocator used by `with_dma_frame_allocator`, so that every block it
+/// hands out is reachable for ISA and 32-bit DMA. Returns the number of
+/// frames moved.
+///
+/// Needs the heap and must be called after `memory::init`.
+pub fn init_dma_frame_allocator(frame_allocator: &mut BootInfoFrameAllocator) -> usize {
+    let end = PhysAddr::new(DMA_LIMIT);
+    let mut buddy = unsafe { BuddyFrameAllocator::new(super::physical_memory_offset(), end) };
+    let added = buddy.add_frames_from(frame_allocator, PhysAddr::new(0)..end);
+    interrupts::without_interrupts(|| *DMA_FRAME_ALLOCATOR.lock() = Some(buddy));
+    added
+}
+
+/// Runs `f` with the allocator set up by `init_dma_frame_allocator`, for
+/// drivers that need physically contiguous memory.
+///
+/// Interrupts are disabled meanwhile. Panics if no allocator is set up.
+pub fn with_dma_frame_allocator<R>(f: impl FnOnce(&mut BuddyFrameAllocator) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut guard = DMA_FRAME_ALLOCATOR.lock();
+        f(guard.as_mut().expect("DMA frame allocator not initialized"))
+    })
+}
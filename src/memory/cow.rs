@@ -0,0 +1,117 @@
+use super::{active_level_4_table, GlobalFrameAllocator, PHYSICAL_MEMORY_OFFSET};
+use alloc::collections::BTreeMap;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+        Translate,
+    },
+    VirtAddr,
+};
+
+/// Marks a page that shares its frame and is copied on the first write.
+/// Such pages are mapped read-only.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Marks a page whose frame belongs to the address space, because it was
+/// copied on write.
+pub const OWNED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Reference counts of shared frames. Frames that are not listed here
+/// have a single owner.
+static REF_COUNTS: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
+/// Runs `f` on the reference counts. The page-fault handler takes the lock
+/// as well, so it is only held with interrupts disabled, lest a thread be
+/// preempted while holding it.
+fn with_ref_counts<R>(f: impl FnOnce(&mut BTreeMap<PhysFrame, usize>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut REF_COUNTS.lock()))
+}
+
+/// Number of copy-on-write mappings of `frame`, or 1 if it is not shared.
+pub fn ref_count(frame: PhysFrame) -> usize {
+    with_ref_counts(|ref_counts| ref_counts.get(&frame).copied().unwrap_or(1))
+}
+
+/// Adds a reference to `frame`.
+pub(super) fn share(frame: PhysFrame) {
+    with_ref_counts(|ref_counts| *ref_counts.entry(frame).or_insert(1) += 1);
+}
+
+/// Drops a reference to `frame`; returns whether it was the last one.
+pub(super) fn release(frame: PhysFrame) -> bool {
+    with_ref_counts(|ref_counts| {
+        match ref_counts.get_mut(&frame) {
+            Some(count) if *count > 2 => *count -= 1,
+            Some(_) => {
+                ref_counts.remove(&frame);
+            }
+            None => return true,
+        }
+        false
+    })
+}
+
+/// Releases the frame of a page that is unmapped, freeing it if it
+/// belonged to the address space.
+pub(super) fn release_page(frame: PhysFrame, flags: PageTableFlags) {
+    let free = if flags.contains(COPY_ON_WRITE) {
+        release(frame)
+    } else {
+        flags.contains(OWNED)
+    };
+    if free {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+/// Resolves a write to a copy-on-write page of the active address space.
+///
+/// The last sharer of a frame gets it back writable, everybody else gets
+/// a private copy. Returns `false` if `addr` is not on a copy-on-write
+/// page, i.e. the fault is a real protection violation.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    let physical_memory_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst));
+    let mut mapper = unsafe {
+        OffsetPageTable::new(
+            active_level_4_table(physical_memory_offset),
+            physical_memory_offset,
+        )
+    };
+    let (frame, flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return false,
+    };
+
+    let page = Page::containing_address(addr);
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE | OWNED;
+    if ref_count(frame) == 1 {
+        unsafe { mapper.update_flags(page, flags).unwrap().flush() };
+        return true;
+    }
+
+    let copy = match GlobalFrameAllocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    unsafe {
+        let src: *const u8 = (physical_memory_offset + frame.start_address().as_u64()).as_ptr();
+        let dst: *mut u8 = (physical_memory_offset + copy.start_address().as_u64()).as_mut_ptr();
+        core::ptr::copy_nonoverlapping(src, dst, 4096);
+
+        mapper.unmap(page).unwrap().1.ignore();
+        mapper
+            .map_to(page, copy, flags, &mut GlobalFrameAllocator)
+            .unwrap()
+            .flush();
+    }
+    // the other sharers may have gone away in the meantime
+    release_page(frame, flags | COPY_ON_WRITE);
+    true
+}
@@ -0,0 +1,114 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::paging::PageTableFlags,
+};
+
+/// The page attribute table MSR.
+const IA32_PAT: u32 = 0x277;
+
+/// PAT memory types.
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WB: u64 = 0x06;
+
+/// Our PAT layout. Entries are selected by the PAT, PCD and PWT bits of a
+/// page table entry, in that order. All four cache modes fit into the first
+/// four entries, so the PAT bit is never needed; it would clash with the
+/// huge page bit that `x86_64` refuses in level 1 entries. Entry 3 stays
+/// uncacheable as in the power-on layout, entry 1 is write-combining
+/// instead of write-through and entry 2 write-through instead of UC-.
+const PAT_LAYOUT: u64 = WB | WC << 8 | WT << 16 | UC << 24;
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Caching behaviour of a memory mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal, fully cached memory
+    WriteBack,
+    /// Reads are cached, writes go straight to memory
+    WriteThrough,
+    /// Writes are buffered and combined, reads are not cached; for frame
+    /// buffers. Falls back to `Uncached` without PAT support.
+    WriteCombining,
+    /// Every access goes to the device; for device registers
+    Uncached,
+}
+
+impl CacheMode {
+    /// The PCD and PWT bits selecting this mode's PAT entry, or the
+    /// closest power-on entry if `init_pat` found no PAT.
+    pub fn flags(self) -> PageTableFlags {
+        let no_cache = PageTableFlags::NO_CACHE;
+        let write_through = PageTableFlags::WRITE_THROUGH;
+        match (self, PAT_ENABLED.load(Ordering::SeqCst)) {
+            (CacheMode::WriteBack, _) => PageTableFlags::empty(),
+            (CacheMode::WriteThrough, true) => no_cache,
+            (CacheMode::WriteThrough, false) => write_through,
+            (CacheMode::WriteCombining, true) => write_through,
+            (CacheMode::WriteCombining, false) | (CacheMode::Uncached, _) => {
+                no_cache | write_through
+            }
+        }
+    }
+}
+
+/// Whether the CPU has a page attribute table.
+pub fn supports_pat() -> bool {
+    // `__cpuid` is only unsafe on older toolchains
+    #[allow(unused_unsafe)]
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.edx & (1 << 16) != 0
+}
+
+/// Loads our PAT layout on the calling CPU.
+///
+/// Must run on every CPU before it touches an MMIO mapping, since the
+/// layout has to be the same everywhere. Mappings with only the PWT bit
+/// set become write-combining, so nothing may use that bit before.
+///
+/// Follows the sequence the SDM prescribes for changing memory types, so
+/// that no cache line or TLB entry outlives the switch with its old type.
+pub fn init_pat() {
+    if !supports_pat() {
+        return;
+    }
+    interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        // no-fill cache mode
+        Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+        flush_caches_and_tlb();
+        // the upper four entries mirror the lower ones
+        Msr::new(IA32_PAT).write(PAT_LAYOUT | PAT_LAYOUT << 32);
+        flush_caches_and_tlb();
+        Cr0::write(cr0);
+    });
+    PAT_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Writes back and invalidates all caches, then flushes the whole TLB,
+/// including global pages.
+unsafe fn flush_caches_and_tlb() {
+    asm!("wbinvd", options(nostack, preserves_flags));
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+        Cr4::write(cr4);
+    } else {
+        tlb::flush_all();
+    }
+}
+
+/// The current content of the PAT MSR of the calling CPU.
+pub fn read_pat() -> u64 {
+    unsafe { Msr::new(IA32_PAT).read() }
+}
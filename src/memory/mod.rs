@@ -0,0 +1,599 @@
+pub mod address_space;
+pub mod buddy;
+pub mod cow;
+pub mod mmio;
+pub mod sections;
+pub mod vma;
+pub mod walker;
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::MapToError, page_table::FrameError, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+        Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Virtual address range for user mode mappings. The kernel itself never
+/// maps anything here.
+pub const USER_SPACE_START: usize = 0x_1000_0000_0000;
+pub const USER_SPACE_END: usize = 0x_2000_0000_0000;
+
+/// Physical address of the level 4 table set up by the bootloader.
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+/// The offset passed to `init`, for code that has no mapper at hand.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level4_table_frame, _) = Cr3::read();
+    let addr = physical_memory_offset + level4_table_frame.start_address().as_u64();
+    let page_table_ptr: *mut PageTable = addr.as_mut_ptr();
+    &mut *page_table_ptr
+}
+
+pub unsafe fn virttual_to_physical_addr(
+    address: VirtAddr,
+    physical_memory_offset: VirtAddr,
+) -> Option<PhysAddr> {
+    let page_table_indices = [
+        address.p4_index(),
+        address.p3_index(),
+        address.p2_index(),
+        address.p1_index(),
+    ];
+
+    let (level4_table_frame, _) = Cr3::read();
+
+    let mut frame = level4_table_frame;
+
+    //let mut table: &PageTable = active_level_4_table(physical_memory_offset);
+
+    for (level, table_index) in page_table_indices.iter().enumerate() {
+        let phy_addr = frame.start_address();
+        let next_addr = physical_memory_offset + phy_addr.as_u64();
+        let page_table_ptr: *const PageTable = next_addr.as_ptr();
+        let table = &*page_table_ptr;
+
+        let entry = &table[*table_index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => {
+                // a huge page ends the walk early: level 3 entries map 1 GiB
+                // pages, level 2 entries map 2 MiB pages
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => return None,
+                };
+                return Some(entry.addr() + (address.as_u64() & (page_size - 1)));
+            }
+        };
+    }
+
+    Some(frame.start_address() + u64::from(address.page_offset()))
+}
+
+/// Initialize a new OffsetPageTable.
+///
+/// Also runs `init_cpu` for the boot processor, as the page table flags
+/// the kernel maps with rely on it.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let (level_4_table_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::SeqCst,
+    );
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
+    init_cpu();
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Enables the paging features the kernel relies on, on the calling CPU:
+/// write protection in ring 0, so that read-only pages fault for
+/// copy-on-write, the no-execute bit and our PAT layout (see `mmio`).
+///
+/// Must run on every CPU before it touches kernel mappings; `init` does
+/// this for the boot processor.
+pub fn init_cpu() {
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+    mmio::init_pat();
+}
+
+/// The offset of the physical memory window, as passed to `init`.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+}
+
+/// A mapper for the level 4 table set up by the bootloader, for code that
+/// has none passed in, like the heap growing itself.
+///
+/// This function is unsafe because the returned mapper aliases all other
+/// mappers of that table: the caller must make sure that they are not
+/// used at the same time. Must be called after `init`.
+pub unsafe fn kernel_mapper() -> OffsetPageTable<'static> {
+    let physical_memory_offset = physical_memory_offset();
+    let level_4_table = PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::SeqCst));
+    let table_ptr: *mut PageTable = (physical_memory_offset + level_4_table.as_u64()).as_mut_ptr();
+    OffsetPageTable::new(&mut *table_ptr, physical_memory_offset)
+}
+
+/// Creates an example mapping for the given page to the frame containing
+/// `0xb8000`.
+///
+/// For huge pages that frame starts at address 0, so the VGA buffer ends up
+/// at offset `0xb8000` into the page.
+pub fn create_example_mapping<S: PageSize + core::fmt::Debug>(
+    page: Page<S>,
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+
+    let map_to_result = unsafe {
+        // FIXME: this is not safe, we do it only for testing
+        mapper.map_to(page, frame, flags, frame_allocator)
+    };
+    map_to_result.expect("map_to failed").flush();
+}
+
+/// Whether the CPU supports 1 GiB pages.
+pub fn supports_1gib_pages() -> bool {
+    // `__cpuid` is only unsafe on older toolchains
+    #[allow(unused_unsafe)]
+    let extended_features = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) };
+    extended_features.edx & (1 << 26) != 0
+}
+
+/// Maps the `size` bytes of physical memory at `phys_start` to `start`.
+///
+/// Uses 1 GiB and 2 MiB pages wherever both addresses are aligned to them
+/// and enough of the range is left, and 4 KiB pages for the rest.
+///
+/// This function is unsafe because the caller must guarantee that mapping
+/// the physical range does not break memory safety, e.g. by aliasing
+/// memory that is in use otherwise.
+pub unsafe fn map_physical_range(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    phys_start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let huge_1gib = supports_1gib_pages();
+    let mut offset = 0;
+    while offset < size {
+        let virt = start + offset;
+        let phys = phys_start + offset;
+        let fits = |page_size: u64| {
+            virt.is_aligned(page_size) && phys.is_aligned(page_size) && size - offset >= page_size
+        };
+        offset += if huge_1gib && fits(Size1GiB::SIZE) {
+            map_page::<Size1GiB>(mapper, virt, phys, flags, frame_allocator)?
+        } else if fits(Size2MiB::SIZE) {
+            map_page::<Size2MiB>(mapper, virt, phys, flags, frame_allocator)?
+        } else {
+            map_page::<Size4KiB>(mapper, virt, phys, flags, frame_allocator)?
+        };
+    }
+    Ok(())
+}
+
+/// Maps `size` bytes at `start` to newly allocated frames.
+///
+/// Uses 2 MiB pages where `start` is aligned and enough of the range is
+/// left, as long as the frame allocator has free 2 MiB frames, and 4 KiB
+/// pages for the rest.
+pub fn map_allocated_range(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_allocated_prefix(mapper, start, size, flags, frame_allocator).1
+}
+
+/// Like `map_allocated_range`, but also returns how many bytes from
+/// `start` on are mapped when it stops at an error.
+pub fn map_allocated_prefix(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> (u64, Result<(), MapToError<Size4KiB>>) {
+    let mut offset = 0;
+    while offset < size {
+        let virt = start + offset;
+        let huge_frame = if virt.is_aligned(Size2MiB::SIZE) && size - offset >= Size2MiB::SIZE {
+            frame_allocator.allocate_huge_frame()
+        } else {
+            None
+        };
+        let result = unsafe {
+            match huge_frame {
+                Some(frame) => {
+                    let phys = frame.start_address();
+                    let result = map_page::<Size2MiB>(mapper, virt, phys, flags, frame_allocator);
+                    if result.is_err() {
+                        frame_allocator.deallocate_huge_frame(frame);
+                    }
+                    result
+                }
+                None => match frame_allocator.allocate_frame() {
+                    Some(frame) => {
+                        let phys = frame.start_address();
+                        let result =
+                            map_page::<Size4KiB>(mapper, virt, phys, flags, frame_allocator);
+                        if result.is_err() {
+                            frame_allocator.deallocate_frame(frame);
+                        }
+                        result
+                    }
+                    None => Err(MapToError::FrameAllocationFailed),
+                },
+            }
+        };
+        match result {
+            Ok(page_size) => offset += page_size,
+            Err(err) => return (offset, Err(err)),
+        }
+    }
+    (offset, Ok(()))
+}
+
+/// Maps a single page of size `S` and returns its size.
+unsafe fn map_page<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let page = Page::<S>::containing_address(virt);
+    let frame = PhysFrame::<S>::containing_address(phys);
+    mapper
+        .map_to(page, frame, flags, frame_allocator)
+        .map_err(|err| match err {
+            MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => {
+                MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+            }
+        })?
+        .flush();
+    Ok(S::SIZE)
+}
+
+/// A FrameAllocator that always returns `None`.
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+/// Physical memory below this address is never handed out by
+/// `BootInfoFrameAllocator`; it is kept for real-mode code such as the
+/// application processor trampoline.
+pub const LOW_MEMORY_END: u64 = 0x10_0000; // 1 MiB
+
+/// Returns the first usable frame in low memory, skipping frame zero.
+pub fn low_memory_frame(memory_map: &MemoryMap) -> Option<PhysFrame> {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|r| r.range.start_addr().max(0x1000)..r.range.end_addr().min(LOW_MEMORY_END))
+        .find(|r| r.end >= r.start + 4096)
+        .map(|r| PhysFrame::containing_address(PhysAddr::new(r.start)))
+}
+
+/// Returns the end of the highest region in the memory map, which is also
+/// the end of the physical memory window.
+pub fn physical_memory_end(memory_map: &MemoryMap) -> u64 {
+    memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the usable address ranges of the memory map above `LOW_MEMORY_END`.
+fn usable_ranges(memory_map: &MemoryMap) -> impl Iterator<Item = Range<u64>> + '_ {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|r| r.range.start_addr().max(LOW_MEMORY_END)..r.range.end_addr())
+        .filter(|r| r.start < r.end)
+}
+
+/// Bitmap words covering the frames of one 2 MiB page.
+const HUGE_FRAME_WORDS: usize = 512 / 64;
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// It keeps one bit per physical frame (set = in use) in a bitmap that
+/// lives in the first usable region large enough to hold it, followed by
+/// one bit per 2 MiB chunk handed out by `allocate_huge_frame`.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    bitmap: &'static mut [u64],
+    /// Set for the chunks currently allocated as huge frames.
+    huge_bitmap: &'static mut [u64],
+    /// Frames holding both bitmaps, never handed out.
+    bitmap_frames: Range<usize>,
+    /// Bitmap word at which the next search starts.
+    next_word: usize,
+    usable_frames: usize,
+    free_frames: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused. The complete physical memory must be
+    /// mapped at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let frame_count = usable_ranges(memory_map).map(|r| r.end).max().unwrap_or(0) / 4096;
+        let bitmap_words = (frame_count as usize + 63) / 64;
+        let chunks = (bitmap_words + HUGE_FRAME_WORDS - 1) / HUGE_FRAME_WORDS;
+        let huge_bitmap_words = (chunks + 63) / 64;
+        let bitmap_bytes = ((bitmap_words + huge_bitmap_words) * 8) as u64;
+        let bitmap_start = usable_ranges(memory_map)
+            .find(|r| r.end - r.start >= bitmap_bytes)
+            .expect("no usable region large enough for the frame bitmap")
+            .start;
+
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, bitmap_words);
+        bitmap.fill(u64::MAX);
+        let huge_bitmap =
+            core::slice::from_raw_parts_mut(bitmap_ptr.add(bitmap_words), huge_bitmap_words);
+        huge_bitmap.fill(0);
+
+        let bitmap_end = bitmap_start + bitmap_bytes;
+        let mut allocator = BootInfoFrameAllocator {
+            memory_map,
+            bitmap,
+            huge_bitmap,
+            bitmap_frames: (bitmap_start / 4096) as usize..((bitmap_end + 4095) / 4096) as usize,
+            next_word: 0,
+            usable_frames: 0,
+            free_frames: 0,
+        };
+        for range in usable_ranges(memory_map) {
+            for frame in range.start / 4096..range.end / 4096 {
+                allocator.set_free(frame as usize);
+            }
+        }
+        allocator.usable_frames = allocator.free_frames;
+
+        // the bitmap itself is not free
+        for frame in allocator.bitmap_frames.clone() {
+            allocator.set_used(frame);
+        }
+        allocator
+    }
+
+    /// Whether `frame` is one this allocator hands out: in a usable range
+    /// and not part of the bitmap.
+    fn is_managed(&self, frame: usize) -> bool {
+        let frame_u64 = frame as u64;
+        frame < self.bitmap.len() * 64
+            && !self.bitmap_frames.contains(&frame)
+            && usable_ranges(self.memory_map)
+                .any(|r| r.start / 4096 <= frame_u64 && frame_u64 < r.end / 4096)
+    }
+
+    /// Whether the 2 MiB chunk containing `frame` was handed out by
+    /// `allocate_huge_frame`.
+    fn is_huge(&self, frame: usize) -> bool {
+        let chunk = frame / 512;
+        self.huge_bitmap[chunk / 64] & (1 << (chunk % 64)) != 0
+    }
+
+    fn set_huge(&mut self, frame: usize, huge: bool) {
+        let chunk = frame / 512;
+        if huge {
+            self.huge_bitmap[chunk / 64] |= 1 << (chunk % 64);
+        } else {
+            self.huge_bitmap[chunk / 64] &= !(1 << (chunk % 64));
+        }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        debug_assert!(!self.is_used(frame));
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+        self.free_frames -= 1;
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        debug_assert!(self.is_used(frame));
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+        self.free_frames += 1;
+    }
+
+    /// Number of usable frames managed by this allocator.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames currently allocated, including the bitmap itself.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Allocates the given frame if it is free; returns whether it was.
+    ///
+    /// Used to hand specific physical ranges to other allocators.
+    pub fn claim(&mut self, frame: PhysFrame) -> bool {
+        if self.is_allocated(frame) {
+            return false;
+        }
+        self.set_used((frame.start_address().as_u64() / 4096) as usize);
+        true
+    }
+
+    /// Allocates 512 contiguous frames for a 2 MiB page.
+    ///
+    /// Only chunks that are completely free are considered, so this may
+    /// fail even though enough single frames are left.
+    pub fn allocate_huge_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let first = (0..self.bitmap.len() / HUGE_FRAME_WORDS)
+            .map(|chunk| chunk * HUGE_FRAME_WORDS)
+            .find(|&first| {
+                self.bitmap[first..first + HUGE_FRAME_WORDS]
+                    .iter()
+                    .all(|&word| word == 0)
+            })?;
+        self.bitmap[first..first + HUGE_FRAME_WORDS].fill(u64::MAX);
+        self.set_huge(first * 64, true);
+        self.free_frames -= HUGE_FRAME_WORDS * 64;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            first as u64 * 64 * 4096,
+        )))
+    }
+
+    /// Frees a frame returned by `allocate_huge_frame`.
+    ///
+    /// Panics for any other 2 MiB chunk, even if all of its frames are in
+    /// use, e.g. because they were allocated one by one.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// frame is no longer in use.
+    pub unsafe fn deallocate_huge_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let number = (frame.start_address().as_u64() / 4096) as usize;
+        assert!(
+            number < self.bitmap.len() * 64 && self.is_huge(number),
+            "{:?} was not allocated by this allocator",
+            frame
+        );
+        self.set_huge(number, false);
+        let first = number / 64;
+        self.bitmap[first..first + HUGE_FRAME_WORDS].fill(0);
+        self.free_frames += HUGE_FRAME_WORDS * 64;
+    }
+
+    /// Whether `frame` is currently allocated (or not usable at all).
+    pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+        let number = (frame.start_address().as_u64() / 4096) as usize;
+        number >= self.bitmap.len() * 64 || self.is_used(number)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        let words = self.bitmap.len();
+        let index = (0..words)
+            .map(|i| (self.next_word + i) % words)
+            .find(|&index| self.bitmap[index] != u64::MAX)?;
+        let frame = index * 64 + (!self.bitmap[index]).trailing_zeros() as usize;
+
+        self.set_used(frame);
+        self.next_word = index;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame as u64 * 4096,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let number = (frame.start_address().as_u64() / 4096) as usize;
+        assert!(
+            self.is_managed(number),
+            "{:?} was not allocated by this allocator",
+            frame
+        );
+        assert!(
+            !self.is_huge(number),
+            "{:?} is part of a huge frame, free that with deallocate_huge_frame",
+            frame
+        );
+        assert!(self.is_used(number), "double free of {:?}", frame);
+        self.set_free(number);
+        // reuse the frame right away while it is likely still cached
+        self.next_word = number / 64;
+    }
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// Hands the frame allocator over to `GlobalFrameAllocator`, for code that
+/// cannot get one passed in, like `Drop` impls.
+pub fn init_global_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Runs `f` with the frame allocator installed by
+/// `init_global_frame_allocator`.
+///
+/// Interrupts are disabled meanwhile, so that interrupt handlers can use the
+/// allocator too. Panics if no allocator is installed.
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> R {
+    try_with_frame_allocator(f).expect("global frame allocator not initialized")
+}
+
+/// Like `with_frame_allocator`, but returns `None` if no allocator is
+/// installed yet.
+pub fn try_with_frame_allocator<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut().map(f))
+}
+
+/// Handle to the frame allocator installed by `init_global_frame_allocator`.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        with_frame_allocator(|frame_allocator| frame_allocator.deallocate_frame(frame))
+    }
+}
@@ -0,0 +1,160 @@
+use super::walker::{self, Mapping};
+use alloc::vec::Vec;
+use bootloader::bootinfo::MemoryMap;
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{
+        mapper::FlagUpdateError, Mapper, OffsetPageTable, Page, PageTableFlags, Size1GiB, Size2MiB,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+extern "C" {
+    // defined in linker.ld, all page aligned
+    static __kernel_start: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __kernel_end: u8;
+}
+
+fn symbol_addr(symbol: &u8) -> VirtAddr {
+    VirtAddr::from_ptr(symbol as *const u8)
+}
+
+/// Section boundaries of the kernel image.
+#[derive(Debug, Clone)]
+pub struct KernelSections {
+    pub rodata: Range<VirtAddr>,
+    pub text: Range<VirtAddr>,
+    /// `.data` and `.bss`
+    pub data: Range<VirtAddr>,
+}
+
+impl KernelSections {
+    pub fn get() -> Self {
+        unsafe {
+            KernelSections {
+                rodata: symbol_addr(&__rodata_start)..symbol_addr(&__rodata_end),
+                text: symbol_addr(&__text_start)..symbol_addr(&__text_end),
+                data: symbol_addr(&__data_start)..symbol_addr(&__data_end),
+            }
+        }
+    }
+
+    /// Page table flags for the page at `addr` of the kernel image: code is
+    /// read-only and executable, everything else is not executable and only
+    /// `.data` and `.bss` are writable.
+    fn flags(&self, addr: VirtAddr, flags: PageTableFlags) -> PageTableFlags {
+        let flags = flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE;
+        if self.text.contains(&addr) {
+            flags
+        } else if self.data.contains(&addr) {
+            flags | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+        } else {
+            flags | PageTableFlags::NO_EXECUTE
+        }
+    }
+}
+
+/// Virtual address range of the whole kernel image.
+pub fn kernel_image() -> Range<VirtAddr> {
+    unsafe { symbol_addr(&__kernel_start)..symbol_addr(&__kernel_end) }
+}
+
+/// Remaps the kernel so that no page is both writable and executable.
+///
+/// The kernel image gets per-section permissions, and the boot stack, the
+/// physical memory window and all other writable pages the bootloader
+/// mapped, like the VGA buffer, become non-executable. The heap and other
+/// stacks are mapped non-executable when they are created. Needs the heap
+/// and the no-execute bit enabled by `memory::init`.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at the mapper's offset and that
+/// nothing relies on executing data or writing code.
+pub unsafe fn protect_kernel(
+    mapper: &mut OffsetPageTable,
+    memory_map: &MemoryMap,
+) -> Result<(), FlagUpdateError> {
+    let physical_memory_offset = mapper.phys_offset();
+    let window =
+        physical_memory_offset..physical_memory_offset + super::physical_memory_end(memory_map);
+    let image = kernel_image();
+    let sections = KernelSections::get();
+
+    let mut leaves = Vec::new();
+    walker::walk(
+        super::active_level_4_table(physical_memory_offset),
+        physical_memory_offset,
+        |leaf| leaves.push(leaf),
+    );
+    let stack = boot_stack(&leaves);
+
+    for leaf in leaves.iter() {
+        let flags = if image.contains(&leaf.start) {
+            sections.flags(leaf.start, leaf.flags)
+        } else if window.contains(&leaf.start)
+            || stack.contains(&leaf.start)
+            || leaf.flags.contains(PageTableFlags::WRITABLE)
+        {
+            leaf.flags | PageTableFlags::NO_EXECUTE
+        } else {
+            continue;
+        };
+        if flags != leaf.flags {
+            update_flags(mapper, leaf, flags)?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds the stack we are running on: the run of virtually contiguous
+/// leaves with equal flags around a local variable.
+fn boot_stack(leaves: &[Mapping]) -> Range<VirtAddr> {
+    let marker = 0u8;
+    let marker = VirtAddr::from_ptr(&marker);
+    let index = leaves
+        .iter()
+        .position(|leaf| leaf.start <= marker && marker < leaf.end())
+        .expect("stack is not mapped");
+
+    let continues = |a: &Mapping, b: &Mapping| a.end() == b.start && a.flags == b.flags;
+    let mut first = index;
+    while first > 0 && continues(&leaves[first - 1], &leaves[first]) {
+        first -= 1;
+    }
+    let mut last = index;
+    while last + 1 < leaves.len() && continues(&leaves[last], &leaves[last + 1]) {
+        last += 1;
+    }
+    leaves[first].start..leaves[last].end()
+}
+
+fn update_flags(
+    mapper: &mut OffsetPageTable,
+    leaf: &Mapping,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    unsafe {
+        match leaf.size {
+            4096 => {
+                let page: Page<Size4KiB> = Page::containing_address(leaf.start);
+                mapper.update_flags(page, flags)?.flush();
+            }
+            0x20_0000 => {
+                let page: Page<Size2MiB> = Page::containing_address(leaf.start);
+                mapper.update_flags(page, flags)?.flush();
+            }
+            _ => {
+                let page: Page<Size1GiB> = Page::containing_address(leaf.start);
+                mapper.update_flags(page, flags)?.flush();
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,410 @@
+use super::{mmio::CacheMode, USER_SPACE_END, USER_SPACE_START};
+use alloc::collections::BTreeMap;
+use bootloader::bootinfo::MemoryMap;
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Virtual address range that `reserve` hands out addresses from.
+pub const KERNEL_VMA_START: u64 = 0x_5000_0000_0000;
+pub const KERNEL_VMA_END: u64 = 0x_6000_0000_0000;
+
+/// Unmapped gap that `reserve` leaves around every area, so that stack
+/// overflows and the like fault instead of running into a neighbour.
+const GUARD_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub enum VmaError {
+    /// Start or size is not page aligned, or the size is zero
+    Invalid,
+    /// The range overlaps the named area or protected region
+    Overlap(&'static str),
+    /// No free range of the requested size is left
+    OutOfSpace,
+    /// No area starts at the given address
+    NotFound,
+    /// The area's pages are not managed by the `VmaManager`
+    NotAnonymous,
+    /// The area was not created by `map_mmio`
+    NotMmio,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    FlagUpdate(FlagUpdateError),
+}
+
+impl From<MapToError<Size4KiB>> for VmaError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        VmaError::Map(err)
+    }
+}
+
+impl From<UnmapError> for VmaError {
+    fn from(err: UnmapError) -> Self {
+        VmaError::Unmap(err)
+    }
+}
+
+impl From<FlagUpdateError> for VmaError {
+    fn from(err: FlagUpdateError) -> Self {
+        VmaError::FlagUpdate(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Only the addresses are reserved; the owner maps them itself.
+    Reserved,
+    /// Backed by zeroed frames that the `VmaManager` mapped and frees again.
+    Anonymous,
+    /// Maps device memory; the frames are never freed.
+    Mmio,
+}
+
+/// A reserved range of kernel virtual addresses.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub kind: VmaKind,
+    pub name: &'static str,
+}
+
+impl Vma {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+}
+
+/// Keeps track of which kernel virtual addresses are in use.
+///
+/// Areas never overlap each other, the physical memory window, the kernel
+/// image or user space. The page tables themselves are passed in by the
+/// caller, like everywhere else in `memory`.
+pub struct VmaManager {
+    physical_memory_offset: VirtAddr,
+    /// Areas keyed by their start address.
+    areas: BTreeMap<u64, Vma>,
+    protected: [(Range<u64>, &'static str); 3],
+}
+
+impl VmaManager {
+    /// Creates a manager without any areas.
+    ///
+    /// The physical memory window is assumed to cover every region of the
+    /// memory map, rounded up to the 2 MiB pages the bootloader maps it with.
+    pub fn new(physical_memory_offset: VirtAddr, memory_map: &MemoryMap) -> Self {
+        let physical_memory_end = super::physical_memory_end(memory_map);
+        let window_start = physical_memory_offset.as_u64();
+        let window_end = window_start + align_up(physical_memory_end, Size2MiB::SIZE);
+
+        let image = super::sections::kernel_image();
+
+        VmaManager {
+            physical_memory_offset,
+            areas: BTreeMap::new(),
+            protected: [
+                (window_start..window_end, "physical memory window"),
+                (image.start.as_u64()..image.end.as_u64(), "kernel image"),
+                (USER_SPACE_START as u64..USER_SPACE_END as u64, "user space"),
+            ],
+        }
+    }
+
+    /// Reserves `size` bytes at a free address, surrounded by guard pages.
+    pub fn reserve(&mut self, size: u64, name: &'static str) -> Result<VirtAddr, VmaError> {
+        self.insert_free(size, PageTableFlags::empty(), VmaKind::Reserved, name)
+    }
+
+    /// Reserves `size` bytes at the fixed address `start`, for regions
+    /// that were mapped before the heap existed.
+    ///
+    /// Fails with `Invalid` if the range does not end at a canonical
+    /// address, and with `Overlap` if it overlaps anything else.
+    pub fn reserve_fixed(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        name: &'static str,
+    ) -> Result<(), VmaError> {
+        if !start.is_aligned(Size4KiB::SIZE) || !is_valid_size(size) {
+            return Err(VmaError::Invalid);
+        }
+        let end = start
+            .as_u64()
+            .checked_add(size)
+            .filter(|&end| VirtAddr::try_new(end).is_ok())
+            .ok_or(VmaError::Invalid)?;
+        let range = start.as_u64()..end;
+        if let Some((_, other)) = self.conflict(&range) {
+            return Err(VmaError::Overlap(other));
+        }
+        self.insert(range, PageTableFlags::empty(), VmaKind::Reserved, name);
+        Ok(())
+    }
+
+    /// Reserves `size` bytes and maps them to newly allocated, zeroed frames.
+    ///
+    /// Anonymous memory is never executable.
+    pub fn map_anonymous(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        name: &'static str,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<VirtAddr, VmaError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        let start = self.insert_free(size, flags, VmaKind::Anonymous, name)?;
+        let area = self.areas[&start.as_u64()].clone();
+        let physical_memory_offset = self.physical_memory_offset;
+
+        for page in area.pages() {
+            let result = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)
+                .and_then(|frame| {
+                    let frame_ptr: *mut u8 =
+                        (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+                    unsafe {
+                        frame_ptr.write_bytes(0, Size4KiB::SIZE as usize);
+                        mapper
+                            .map_to(page, frame, flags, frame_allocator)
+                            .map_err(|err| {
+                                // never mapped, so `unmap` would not free it
+                                frame_allocator.deallocate_frame(frame);
+                                err
+                            })
+                    }
+                });
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    // give back what was mapped so far; the mapping error is
+                    // the one worth reporting
+                    let _ = self.unmap(start, mapper, frame_allocator);
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(start)
+    }
+
+    /// Removes the area starting at `start` and unmaps its pages.
+    ///
+    /// The frames of anonymous areas are freed; those of reserved and MMIO
+    /// areas belong to their owner and are left alone.
+    pub fn unmap(
+        &mut self,
+        start: VirtAddr,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), VmaError> {
+        let area = self
+            .areas
+            .remove(&start.as_u64())
+            .ok_or(VmaError::NotFound)?;
+        for page in area.pages() {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    if area.kind == VmaKind::Anonymous {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the `len` bytes of device memory at `phys` with `cache_mode`
+    /// and returns the virtual address of `phys`.
+    ///
+    /// MMIO mappings are writable and never executable.
+    ///
+    /// This function is unsafe because the caller must guarantee that
+    /// `phys` is device memory, or memory that nobody else maps with a
+    /// different cache mode.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn map_mmio(
+        &mut self,
+        phys: PhysAddr,
+        len: u64,
+        cache_mode: CacheMode,
+        name: &'static str,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<VirtAddr, VmaError> {
+        if len == 0 {
+            return Err(VmaError::Invalid);
+        }
+        let phys_start = phys.align_down(Size4KiB::SIZE);
+        let size = align_up(phys.as_u64() + len, Size4KiB::SIZE) - phys_start.as_u64();
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | cache_mode.flags();
+        let start = self.insert_free(size, flags, VmaKind::Mmio, name)?;
+
+        for offset in (0..size).step_by(Size4KiB::SIZE as usize) {
+            let page = Page::containing_address(start + offset);
+            let frame = PhysFrame::containing_address(phys_start + offset);
+            match mapper.map_to(page, frame, flags, frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    // give back what was mapped so far; the mapping error is
+                    // the one worth reporting
+                    let _ = self.unmap_mmio(start, mapper);
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(start + (phys - phys_start))
+    }
+
+    /// Removes the MMIO area containing `addr`, as returned by `map_mmio`.
+    pub fn unmap_mmio(
+        &mut self,
+        addr: VirtAddr,
+        mapper: &mut OffsetPageTable,
+    ) -> Result<(), VmaError> {
+        let area = self.find(addr).ok_or(VmaError::NotFound)?;
+        if area.kind != VmaKind::Mmio {
+            return Err(VmaError::NotMmio);
+        }
+        let area = self.areas.remove(&area.start.as_u64()).unwrap();
+        for page in area.pages() {
+            match mapper.unmap(page) {
+                Ok((_, flush)) => flush.flush(),
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the page table flags of the anonymous area starting at `start`.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        flags: PageTableFlags,
+        mapper: &mut OffsetPageTable,
+    ) -> Result<(), VmaError> {
+        let area = self
+            .areas
+            .get_mut(&start.as_u64())
+            .ok_or(VmaError::NotFound)?;
+        if area.kind != VmaKind::Anonymous {
+            return Err(VmaError::NotAnonymous);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        for page in area.pages() {
+            unsafe { mapper.update_flags(page, flags)?.flush() };
+        }
+        area.flags = flags;
+        Ok(())
+    }
+
+    /// Returns the area containing `addr`.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(addr))
+    }
+
+    /// Iterates over all areas, ordered by address.
+    pub fn areas(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Reserves the first free range of `size` bytes in the kernel VMA
+    /// range that keeps a guard gap to everything around it.
+    fn insert_free(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        kind: VmaKind,
+        name: &'static str,
+    ) -> Result<VirtAddr, VmaError> {
+        if !is_valid_size(size) {
+            return Err(VmaError::Invalid);
+        }
+        let mut start = KERNEL_VMA_START + GUARD_SIZE;
+        loop {
+            let end = start.checked_add(size).ok_or(VmaError::OutOfSpace)?;
+            if end + GUARD_SIZE > KERNEL_VMA_END {
+                return Err(VmaError::OutOfSpace);
+            }
+            match self.conflict(&(start - GUARD_SIZE..end + GUARD_SIZE)) {
+                Some((conflict_end, _)) => start = conflict_end + GUARD_SIZE,
+                None => break,
+            }
+        }
+        self.insert(start..start + size, flags, kind, name);
+        Ok(VirtAddr::new(start))
+    }
+
+    fn insert(
+        &mut self,
+        range: Range<u64>,
+        flags: PageTableFlags,
+        kind: VmaKind,
+        name: &'static str,
+    ) {
+        let area = Vma {
+            start: VirtAddr::new(range.start),
+            end: VirtAddr::new(range.end),
+            flags,
+            kind,
+            name,
+        };
+        self.areas.insert(range.start, area);
+    }
+
+    /// Returns the end and name of an area or protected region overlapping
+    /// `range`, if there is one.
+    fn conflict(&self, range: &Range<u64>) -> Option<(u64, &'static str)> {
+        let protected = self
+            .protected
+            .iter()
+            .map(|(region, name)| (region.clone(), *name));
+        let areas = self
+            .areas
+            .values()
+            .map(|area| (area.start.as_u64()..area.end.as_u64(), area.name));
+        protected
+            .chain(areas)
+            .filter(|(other, _)| other.start < range.end && range.start < other.end)
+            .map(|(other, name)| (other.end, name))
+            .max_by_key(|(end, _)| *end)
+    }
+}
+
+fn is_valid_size(size: u64) -> bool {
+    size != 0 && size % Size4KiB::SIZE == 0
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
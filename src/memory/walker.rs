@@ -0,0 +1,247 @@
+use crate::{println, sprintln};
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+/// Flags that change on every access and would only add noise.
+const IGNORED_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+/// A virtually and physically contiguous range of pages with equal flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    /// Flags of the leaf entries, without accessed and dirty bits.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Whether `next` continues this mapping virtually and physically.
+    fn is_continued_by(&self, next: &Mapping) -> bool {
+        self.end() == next.start && self.phys + self.size == next.phys && self.flags == next.flags
+    }
+}
+
+/// Formats as `start-end -> phys size flags`, where the flags are `W`
+/// (writable), `U` (user accessible), `NX` (no execute), `H` (huge page)
+/// and `G` (global), or `-` for each one that is missing.
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, name| if self.flags.contains(flag) { name } else { "-" };
+        write!(
+            f,
+            "{:#014x}-{:#014x} -> {:#012x} {:>9} {}{}{}{}{}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.phys.as_u64(),
+            Size(self.size),
+            flag(PageTableFlags::WRITABLE, "W"),
+            flag(PageTableFlags::USER_ACCESSIBLE, "U"),
+            flag(PageTableFlags::NO_EXECUTE, "NX"),
+            flag(PageTableFlags::HUGE_PAGE, "H"),
+            flag(PageTableFlags::GLOBAL, "G"),
+        )
+    }
+}
+
+/// A size in bytes, printed with the largest unit that divides it.
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")];
+        let text = match units.iter().find(|(unit, _)| self.0 % unit == 0) {
+            Some((unit, name)) => alloc::format!("{} {}", self.0 / unit, name),
+            None => alloc::format!("{} B", self.0),
+        };
+        f.pad(&text)
+    }
+}
+
+/// Calls `f` for every present leaf entry below `level_4_table`, ordered
+/// by virtual address.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at `physical_memory_offset`.
+pub unsafe fn walk(
+    level_4_table: &PageTable,
+    physical_memory_offset: VirtAddr,
+    mut f: impl FnMut(Mapping),
+) {
+    walk_table(level_4_table, 4, 0, physical_memory_offset, &mut f);
+}
+
+unsafe fn walk_table(
+    table: &PageTable,
+    level: u64,
+    base: u64,
+    physical_memory_offset: VirtAddr,
+    f: &mut impl FnMut(Mapping),
+) {
+    let entry_size = 4096u64 << (9 * (level - 1));
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = VirtAddr::new_truncate(base + index as u64 * entry_size);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            // in level 1 entries, the huge page bit selects the memory type
+            let flags = match level {
+                1 => flags - IGNORED_FLAGS - PageTableFlags::HUGE_PAGE,
+                _ => flags - IGNORED_FLAGS,
+            };
+            f(Mapping {
+                start,
+                phys: entry.addr(),
+                size: entry_size,
+                flags,
+            });
+        } else {
+            let next: *const PageTable = (physical_memory_offset + entry.addr().as_u64()).as_ptr();
+            let base = base + index as u64 * entry_size;
+            walk_table(&*next, level - 1, base, physical_memory_offset, f);
+        }
+    }
+}
+
+/// Flags that only take effect if the entries of all levels have them.
+const ALL_LEVEL_FLAGS: PageTableFlags =
+    PageTableFlags::WRITABLE.union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Returns the flags that apply to `addr` below `level_4_table`, or `None`
+/// if it is not mapped.
+///
+/// Unlike the flags of the leaf entry, these have `WRITABLE` and
+/// `USER_ACCESSIBLE` only if the entries of all levels have them, and
+/// `NO_EXECUTE` if the entry of any level has it.
+///
+/// This function is unsafe for the same reason as `walk`.
+pub unsafe fn effective_flags(
+    level_4_table: &PageTable,
+    addr: VirtAddr,
+    physical_memory_offset: VirtAddr,
+) -> Option<PageTableFlags> {
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table = level_4_table;
+    let mut granted = ALL_LEVEL_FLAGS;
+    let mut no_execute = PageTableFlags::empty();
+    for (level, &index) in (1..=4).rev().zip(indexes.iter()) {
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        granted &= flags;
+        no_execute |= flags & PageTableFlags::NO_EXECUTE;
+        if level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            // in level 1 entries, the huge page bit selects the memory type
+            let leaf = match level {
+                1 => flags - PageTableFlags::HUGE_PAGE,
+                _ => flags,
+            };
+            return Some(leaf - IGNORED_FLAGS - ALL_LEVEL_FLAGS | granted | no_execute);
+        }
+        table = &*(physical_memory_offset + entry.addr().as_u64()).as_ptr();
+    }
+    unreachable!("level 1 entries are always leaves")
+}
+
+/// Returns all mappings below `level_4_table`, with contiguous pages merged.
+///
+/// This function is unsafe for the same reason as `walk`.
+pub unsafe fn mappings(
+    level_4_table: &PageTable,
+    physical_memory_offset: VirtAddr,
+) -> Vec<Mapping> {
+    let mut mappings: Vec<Mapping> = Vec::new();
+    walk(
+        level_4_table,
+        physical_memory_offset,
+        |mapping| match mappings.last_mut() {
+            Some(last) if last.is_continued_by(&mapping) => last.size += mapping.size,
+            _ => mappings.push(mapping),
+        },
+    );
+    mappings
+}
+
+/// Returns the merged mappings of the active address space.
+///
+/// This function is unsafe for the same reason as `walk`.
+pub unsafe fn active_mappings(physical_memory_offset: VirtAddr) -> Vec<Mapping> {
+    mappings(
+        super::active_level_4_table(physical_memory_offset),
+        physical_memory_offset,
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    Added(&'a Mapping),
+    Removed(&'a Mapping),
+}
+
+/// Compares two snapshots taken with `mappings`, ordered by address.
+///
+/// A mapping that grew or changed its flags shows up as removed in its
+/// old form and added in its new one.
+pub fn diff<'a>(old: &'a [Mapping], new: &'a [Mapping]) -> Vec<Change<'a>> {
+    let removed = old
+        .iter()
+        .filter(|mapping| !new.contains(mapping))
+        .map(Change::Removed);
+    let added = new
+        .iter()
+        .filter(|mapping| !old.contains(mapping))
+        .map(Change::Added);
+    let mut changes: Vec<_> = removed.chain(added).collect();
+    changes.sort_by_key(|change| match change {
+        Change::Removed(mapping) => (mapping.start, 0),
+        Change::Added(mapping) => (mapping.start, 1),
+    });
+    changes
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Serial,
+    Vga,
+}
+
+fn print_line(output: Output, args: fmt::Arguments) {
+    match output {
+        Output::Serial => sprintln!("{}", args),
+        Output::Vga => println!("{}", args),
+    }
+}
+
+/// Prints one line per mapping.
+pub fn dump(mappings: &[Mapping], output: Output) {
+    for mapping in mappings {
+        print_line(output, format_args!("{}", mapping));
+    }
+}
+
+/// Prints the changes between two snapshots, prefixed with `+` or `-`.
+pub fn dump_diff(old: &[Mapping], new: &[Mapping], output: Output) {
+    for change in diff(old, new) {
+        match change {
+            Change::Removed(mapping) => print_line(output, format_args!("- {}", mapping)),
+            Change::Added(mapping) => print_line(output, format_args!("+ {}", mapping)),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr};
+use x86_64::{PhysAddr, VirtAddr};
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+ only
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Processor information found in the MADT.
+#[derive(Debug)]
+pub struct MadtInfo {
+    pub local_apic_address: PhysAddr,
+    /// APIC ids of all enabled (or online capable) processors, in table order.
+    pub apic_ids: Vec<u8>,
+}
+
+/// Reads a `T` from physical memory through the physical memory window.
+unsafe fn read_phys<T: Copy>(physical_memory_offset: VirtAddr, addr: u64) -> T {
+    let ptr: *const T = (physical_memory_offset + addr).as_ptr();
+    ptr::read_unaligned(ptr)
+}
+
+unsafe fn checksum_ok(physical_memory_offset: VirtAddr, addr: u64, len: usize) -> bool {
+    let bytes = (physical_memory_offset + addr).as_ptr::<u8>();
+    let sum = (0..len).fold(0u8, |sum, i| sum.wrapping_add(*bytes.add(i)));
+    sum == 0
+}
+
+/// Searches the EBDA and the BIOS read-only area for the RSDP.
+unsafe fn find_rsdp(physical_memory_offset: VirtAddr) -> Option<Rsdp> {
+    let ebda = u64::from(read_phys::<u16>(physical_memory_offset, 0x40e)) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+
+    for (start, end) in areas.iter().copied().filter(|&(start, _)| start != 0) {
+        for addr in (start..end).step_by(16) {
+            let rsdp: Rsdp = read_phys(physical_memory_offset, addr);
+            if &rsdp.signature == b"RSD PTR " && checksum_ok(physical_memory_offset, addr, 20) {
+                return Some(rsdp);
+            }
+        }
+    }
+    None
+}
+
+/// Finds the system description table with the given signature through
+/// the XSDT (ACPI 2.0+) or the RSDT.
+unsafe fn find_table(physical_memory_offset: VirtAddr, signature: &[u8; 4]) -> Option<u64> {
+    let rsdp = find_rsdp(physical_memory_offset)?;
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+
+    let header: SdtHeader = read_phys(physical_memory_offset, root);
+    let entries = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    let first_entry = root + size_of::<SdtHeader>() as u64;
+
+    (0..entries)
+        .map(|i| {
+            let entry = first_entry + (i * entry_size) as u64;
+            if entry_size == 8 {
+                read_phys::<u64>(physical_memory_offset, entry)
+            } else {
+                u64::from(read_phys::<u32>(physical_memory_offset, entry))
+            }
+        })
+        .find(|&table| {
+            let header: SdtHeader = read_phys(physical_memory_offset, table);
+            &header.signature == signature
+                && checksum_ok(physical_memory_offset, table, header.length as usize)
+        })
+}
+
+/// Parses the MADT ("APIC" table) to find the local APIC and all processors.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at `physical_memory_offset`.
+pub unsafe fn parse_madt(physical_memory_offset: VirtAddr) -> Option<MadtInfo> {
+    let madt = find_table(physical_memory_offset, b"APIC")?;
+    let header: SdtHeader = read_phys(physical_memory_offset, madt);
+
+    // the header is followed by the local APIC address and a flags field
+    let mut local_apic_address = u64::from(read_phys::<u32>(physical_memory_offset, madt + 36));
+    let mut apic_ids = Vec::new();
+
+    let end = madt + u64::from(header.length);
+    let mut entry = madt + 44;
+    while entry + 2 <= end {
+        let entry_type: u8 = read_phys(physical_memory_offset, entry);
+        let entry_len: u8 = read_phys(physical_memory_offset, entry + 1);
+        if entry_len < 2 {
+            break;
+        }
+        match entry_type {
+            MADT_LOCAL_APIC => {
+                let apic_id: u8 = read_phys(physical_memory_offset, entry + 3);
+                let flags: u32 = read_phys(physical_memory_offset, entry + 4);
+                // bit 0: enabled, bit 1: online capable
+                if flags & 0b11 != 0 {
+                    apic_ids.push(apic_id);
+                }
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                local_apic_address = read_phys(physical_memory_offset, entry + 4);
+            }
+            _ => {}
+        }
+        entry += u64::from(entry_len);
+    }
+
+    Some(MadtInfo {
+        local_apic_address: PhysAddr::new(local_apic_address),
+        apic_ids,
+    })
+}
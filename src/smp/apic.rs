@@ -0,0 +1,81 @@
+use core::ptr;
+use x86_64::VirtAddr;
+
+// register offsets
+const ID: usize = 0x20;
+const EOI: usize = 0xb0;
+const SPURIOUS_VECTOR: usize = 0xf0;
+const ERROR_STATUS: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const SPURIOUS_INTERRUPT_VECTOR: u32 = 0xff;
+
+// interrupt command register bits
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const DELIVERY_PENDING: u32 = 1 << 12;
+
+/// The memory mapped local APIC of the current CPU.
+///
+/// Every CPU sees its own local APIC at the same address, so a single
+/// mapping serves all of them.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    /// Creates a handle for a local APIC mapped (uncached) at `base`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// local APIC registers are really mapped at `base`.
+    pub const unsafe fn new(base: VirtAddr) -> Self {
+        LocalApic { base }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        let reg_ptr: *const u32 = (self.base + reg).as_ptr();
+        unsafe { ptr::read_volatile(reg_ptr) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        let reg_ptr: *mut u32 = (self.base + reg).as_mut_ptr();
+        unsafe { ptr::write_volatile(reg_ptr, value) }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// Software-enables the local APIC of the current CPU.
+    pub fn enable(&self) {
+        self.write(SPURIOUS_VECTOR, SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR);
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI, 0);
+    }
+
+    /// Sends an INIT inter-processor interrupt to the CPU with `apic_id`.
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_ipi(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+    }
+
+    /// Sends a STARTUP inter-processor interrupt, which starts the target
+    /// CPU in real mode at physical address `vector * 0x1000`.
+    pub fn send_startup(&self, apic_id: u8, vector: u8) {
+        self.send_ipi(apic_id, DELIVERY_STARTUP | u32::from(vector));
+    }
+
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        // clear stale errors from earlier sends
+        self.write(ERROR_STATUS, 0);
+        self.write(ICR_HIGH, u32::from(apic_id) << 24);
+        self.write(ICR_LOW, command);
+        while self.read(ICR_LOW) & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,268 @@
+pub mod acpi;
+pub mod apic;
+pub mod percpu;
+mod trampoline;
+
+use crate::{
+    gdt, hlt_loop, interrupts,
+    memory::{self, mmio::CacheMode, vma::VmaError, vma::VmaManager},
+    sprintln, syscall,
+};
+use apic::LocalApic;
+use bootloader::BootInfo;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use trampoline::Trampoline;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+// Every AP gets a kernel stack, a double fault stack and a stack for
+// interrupts from ring 3, each above an unmapped guard page.
+const AP_STACK_PAGES: u64 = 4;
+const AP_DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+const AP_PRIVILEGE_STACK_PAGES: u64 = 4;
+const AP_STACKS_STRIDE: u64 =
+    (3 + AP_STACK_PAGES + AP_DOUBLE_FAULT_STACK_PAGES + AP_PRIVILEGE_STACK_PAGES) * 4096;
+
+/// How long to wait for an AP to check in before giving up on it.
+const AP_TIMEOUT_TICKS: u64 = 20;
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+/// Start of the area holding the stacks of all APs.
+static AP_STACKS_START: AtomicU64 = AtomicU64::new(0);
+
+/// Number of processors listed in the ACPI tables (1 before `init`).
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Number of processors that are up and running, including the BSP.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.try_get().ok()
+}
+
+/// Sets up per-CPU data and the system call entry for the bootstrap
+/// processor and starts all application processors listed in the MADT, one
+/// after the other.
+///
+/// Must be called once, after the heap is initialized and with interrupts
+/// enabled, since the INIT-SIPI-SIPI delays are measured in timer ticks.
+pub fn init(
+    boot_info: &'static BootInfo,
+    vmas: &mut VmaManager,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), VmaError> {
+    let physical_memory_offset = mapper.phys_offset();
+    let madt = match unsafe { acpi::parse_madt(physical_memory_offset) } {
+        Some(madt) => madt,
+        None => {
+            sprintln!("Warning: no MADT found; running on the BSP only");
+            percpu::init(0, 0, gdt::bsp_tss());
+            syscall::init();
+            return Ok(());
+        }
+    };
+
+    let apic_base = unsafe {
+        vmas.map_mmio(
+            madt.local_apic_address,
+            4096,
+            CacheMode::Uncached,
+            "local APIC",
+            mapper,
+            frame_allocator,
+        )?
+    };
+
+    let lapic = LOCAL_APIC
+        .try_get_or_init(|| unsafe { LocalApic::new(apic_base) })
+        .expect("smp::init should be called once");
+    lapic.enable();
+    let bsp_apic_id = lapic.id();
+    percpu::init(0, bsp_apic_id, gdt::bsp_tss());
+    syscall::init();
+    CPU_COUNT.store(madt.apic_ids.len().max(1), Ordering::SeqCst);
+
+    let trampoline_frame = memory::low_memory_frame(&boot_info.memory_map)
+        .expect("no free low memory frame for the AP trampoline");
+    let identity_mapped = identity_map(trampoline_frame, mapper, frame_allocator)?;
+    let trampoline = unsafe { Trampoline::install(trampoline_frame, physical_memory_offset) };
+
+    let ap_ids = madt
+        .apic_ids
+        .iter()
+        .copied()
+        .filter(|&id| id != bsp_apic_id);
+    let ap_count = madt.apic_ids.len().saturating_sub(1) as u64;
+    if ap_count > 0 {
+        // stack areas are indexed by CPU id, so CPU 0 has an unused slot
+        let stacks_start = vmas.reserve((ap_count + 1) * AP_STACKS_STRIDE, "AP stacks")?;
+        AP_STACKS_START.store(stacks_start.as_u64(), Ordering::SeqCst);
+    }
+    for (cpu_id, apic_id) in (1..).zip(ap_ids) {
+        let stacks = map_ap_stacks(cpu_id, mapper, frame_allocator)?;
+        trampoline.prepare(cpu_id, stacks.kernel, ap_main);
+
+        if !start_ap(lapic, apic_id, trampoline.vector()) {
+            sprintln!(
+                "Warning: CPU {} (APIC id {}) did not start",
+                cpu_id,
+                apic_id
+            );
+        }
+    }
+
+    // all APs that checked in are done with the trampoline; one that did
+    // not would fault if it still started now
+    if identity_mapped {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+            trampoline_frame.start_address().as_u64(),
+        ));
+        let (_, flush) = mapper.unmap(page)?;
+        flush.flush();
+    }
+    Ok(())
+}
+
+/// Runs the INIT-SIPI-SIPI sequence for one AP and waits for it to check in.
+fn start_ap(lapic: &LocalApic, apic_id: u8, vector: u8) -> bool {
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    lapic.send_init(apic_id);
+    // at least 10 ms; one tick is ~55 ms, but the first may come early
+    interrupts::wait_ticks(2);
+
+    lapic.send_startup(apic_id, vector);
+    if wait_for_ap(1) {
+        return true;
+    }
+    // the second STARTUP IPI is only needed if the first one got lost
+    lapic.send_startup(apic_id, vector);
+    wait_for_ap(AP_TIMEOUT_TICKS)
+}
+
+fn wait_for_ap(timeout_ticks: u64) -> bool {
+    let deadline = interrupts::ticks() + timeout_ticks;
+    while !AP_STARTED.load(Ordering::SeqCst) {
+        if interrupts::ticks() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Rust entry point of the application processors, called by the trampoline.
+extern "C" fn ap_main(cpu_id: usize) -> ! {
+    let stacks = ApStacks::for_cpu(cpu_id);
+    let tss = gdt::init_ap(stacks.double_fault, stacks.privilege);
+    interrupts::init_idt();
+    memory::init_cpu();
+
+    let lapic = local_apic().expect("AP started before the local APIC was mapped");
+    lapic.enable();
+    percpu::init(cpu_id, lapic.id(), tss);
+    syscall::init();
+
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    sprintln!("CPU {} (APIC id {}) checked in", cpu_id, lapic.id());
+
+    // interrupts stay disabled: the PICs only deliver to the BSP
+    hlt_loop();
+}
+
+/// Stack tops of an AP.
+struct ApStacks {
+    kernel: VirtAddr,
+    double_fault: VirtAddr,
+    privilege: VirtAddr,
+}
+
+impl ApStacks {
+    fn for_cpu(cpu_id: usize) -> Self {
+        let start = VirtAddr::new(AP_STACKS_START.load(Ordering::SeqCst));
+        let base = start + cpu_id as u64 * AP_STACKS_STRIDE;
+        let kernel = base + (1 + AP_STACK_PAGES) * 4096;
+        let double_fault = kernel + (1 + AP_DOUBLE_FAULT_STACK_PAGES) * 4096;
+        let privilege = double_fault + (1 + AP_PRIVILEGE_STACK_PAGES) * 4096;
+        ApStacks {
+            kernel,
+            double_fault,
+            privilege,
+        }
+    }
+}
+
+fn map_ap_stacks(
+    cpu_id: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<ApStacks, MapToError<Size4KiB>> {
+    let stacks = ApStacks::for_cpu(cpu_id);
+    let tops = [
+        (stacks.kernel, AP_STACK_PAGES),
+        (stacks.double_fault, AP_DOUBLE_FAULT_STACK_PAGES),
+        (stacks.privilege, AP_PRIVILEGE_STACK_PAGES),
+    ];
+
+    for &(top, pages) in tops.iter() {
+        let top_page: Page<Size4KiB> = Page::containing_address(top);
+        for page in Page::range(top_page - pages, top_page) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+    }
+    Ok(stacks)
+}
+
+/// Identity maps the trampoline frame, so that the AP keeps executing it
+/// right after enabling paging. Returns whether it created the mapping.
+///
+/// The page is read-only: the trampoline is written through the physical
+/// memory window, and once paging is on it only reads its data fields.
+fn identity_map(
+    frame: PhysFrame,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<bool, MapToError<Size4KiB>> {
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match mapper.translate_addr(page.start_address()) {
+        Some(addr) if addr == frame.start_address() => Ok(false),
+        Some(addr) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(addr),
+        )),
+        None => {
+            let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe {
+                mapper
+                    .map_to_with_table_flags(
+                        page,
+                        frame,
+                        PageTableFlags::PRESENT,
+                        table_flags,
+                        frame_allocator,
+                    )?
+                    .flush()
+            };
+            Ok(true)
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use alloc::boxed::Box;
+use core::cell::Cell;
+use x86_64::{registers::model_specific::GsBase, structures::tss::TaskStateSegment, VirtAddr};
+
+/// Data owned by a single CPU, reached through the GS base register.
+///
+/// The first fields are accessed from assembly at fixed offsets (see
+/// `syscall`), so their order must not change.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points back at this struct; must stay the first field so that
+    /// `current` can load it from `gs:[0]`.
+    self_ptr: *const PerCpu,
+    /// Top of the kernel stack the `syscall` entry switches to (`gs:[8]`).
+    kernel_stack_top: Cell<u64>,
+    /// Scratch slot for the user stack pointer on `syscall` entry (`gs:[16]`).
+    #[allow(dead_code)]
+    user_stack: Cell<u64>,
+    /// Kernel stack pointer to return to when user mode exits (`gs:[24]`).
+    user_mode_return: Cell<u64>,
+    /// TSS of this CPU, whose privilege stack entering user mode points at
+    /// the kernel stack top (`gs:[32]`).
+    tss: *mut TaskStateSegment,
+    pub cpu_id: usize,
+    pub apic_id: u8,
+}
+
+impl PerCpu {
+    pub fn kernel_stack_top(&self) -> VirtAddr {
+        VirtAddr::new(self.kernel_stack_top.get())
+    }
+
+    /// Sets the stack used by system calls of the thread running on this CPU.
+    pub fn set_kernel_stack_top(&self, stack_top: VirtAddr) {
+        self.kernel_stack_top.set(stack_top.as_u64());
+    }
+
+    /// Where `exit_user_mode_asm` unwinds to; saved and restored with the
+    /// kernel stack top when threads switch.
+    pub fn user_mode_return(&self) -> u64 {
+        self.user_mode_return.get()
+    }
+
+    pub fn set_user_mode_return(&self, rsp: u64) {
+        self.user_mode_return.set(rsp);
+    }
+
+    /// The stack for interrupts that arrive while this CPU runs in ring 3.
+    pub fn privilege_stack_top(&self) -> VirtAddr {
+        unsafe { (*self.tss).privilege_stack_table[0] }
+    }
+
+    pub fn set_privilege_stack_top(&self, stack_top: VirtAddr) {
+        unsafe { (*self.tss).privilege_stack_table[0] = stack_top };
+    }
+}
+
+/// Allocates the per-CPU data area of the calling CPU and points its GS
+/// base at it.
+///
+/// `tss` is the TSS loaded on this CPU. Must be called exactly once on
+/// every CPU, after the heap is initialized.
+pub fn init(cpu_id: usize, apic_id: u8, tss: *mut TaskStateSegment) {
+    let percpu = Box::leak(Box::new(PerCpu {
+        self_ptr: core::ptr::null(),
+        kernel_stack_top: Cell::new(0),
+        user_stack: Cell::new(0),
+        user_mode_return: Cell::new(0),
+        tss,
+        cpu_id,
+        apic_id,
+    }));
+    percpu.self_ptr = percpu as *const PerCpu;
+    GsBase::write(VirtAddr::from_ptr(percpu.self_ptr));
+}
+
+/// Returns the per-CPU data of the calling CPU.
+///
+/// Panics if `init` was not called on this CPU yet.
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per-CPU data not initialized on this CPU")
+}
+
+/// Like `current`, but returns `None` if `init` was not called on this CPU.
+pub fn try_current() -> Option<&'static PerCpu> {
+    if GsBase::read().as_u64() == 0 {
+        return None;
+    }
+    let percpu: *const PerCpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) percpu, options(nostack, readonly, preserves_flags));
+        Some(&*percpu)
+    }
+}
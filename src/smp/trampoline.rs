@@ -0,0 +1,193 @@
+use core::{arch::global_asm, ptr};
+use x86_64::{
+    registers::{
+        control::Cr3,
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::PhysFrame,
+    VirtAddr,
+};
+
+// Application processors start in 16-bit real mode at the start of the
+// frame named by the STARTUP IPI, with `cs` set to that frame's segment.
+// The trampoline loads a temporary GDT, enables long mode with the kernel's
+// page tables and jumps straight into a 64-bit code segment, from where it
+// calls the Rust entry point on the stack prepared by the BSP.
+//
+// The `ap_trampoline_*` data fields at the end are patched by `install`
+// and `prepare` before each STARTUP IPI.
+global_asm!(
+    r#"
+.section .text.ap_trampoline, "ax"
+.p2align 12
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_long_target
+.global ap_trampoline_long
+.global ap_trampoline_gdtr
+.global ap_trampoline_cr3
+.global ap_trampoline_efer
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_cpu_id
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    lgdt [TRAMPOLINE_GDTR]
+
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    mov eax, [TRAMPOLINE_CR3]
+    mov cr3, eax
+
+    mov ecx, 0xc0000080
+    mov eax, [TRAMPOLINE_EFER]
+    xor edx, edx
+    wrmsr
+
+    mov eax, cr0
+    or eax, 0x80010001
+    mov cr0, eax
+
+    .byte 0x66, 0xea
+ap_trampoline_long_target:
+    .long 0
+    .word 0x08
+
+.code64
+ap_trampoline_long:
+    // null data segments stay valid once the kernel GDT is loaded
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [rip + ap_trampoline_stack]
+    mov rdi, [rip + ap_trampoline_cpu_id]
+    mov rax, [rip + ap_trampoline_entry]
+    call rax
+    ud2
+
+.p2align 3
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+ap_trampoline_gdtr:
+    .word 2 * 8 - 1
+    .long 0
+.p2align 3
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_efer:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu_id:
+    .quad 0
+ap_trampoline_end:
+
+// offsets from the segment base, which is the start of the trampoline
+.set TRAMPOLINE_GDTR, ap_trampoline_gdtr - ap_trampoline_start
+.set TRAMPOLINE_CR3, ap_trampoline_cr3 - ap_trampoline_start
+.set TRAMPOLINE_EFER, ap_trampoline_efer - ap_trampoline_start
+
+.code64
+.section .text
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_long_target: u8;
+    static ap_trampoline_long: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_efer: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu_id: u8;
+}
+
+/// Entry point called by the trampoline once the AP runs in long mode.
+pub type ApEntry = extern "C" fn(cpu_id: usize) -> !;
+
+/// A copy of the trampoline in a low memory frame.
+pub struct Trampoline {
+    frame: PhysFrame,
+    /// Where `frame` is mapped in the physical memory window.
+    virt: VirtAddr,
+}
+
+fn offset_of(symbol: &u8) -> u64 {
+    let start = unsafe { &ap_trampoline_start } as *const u8 as u64;
+    symbol as *const u8 as u64 - start
+}
+
+impl Trampoline {
+    /// Copies the trampoline code into `frame` and patches the
+    /// position-dependent parts for its physical address.
+    ///
+    /// This function is unsafe because `frame` must be a free frame below
+    /// 1 MiB that is identity mapped and also reachable at
+    /// `physical_memory_offset`.
+    pub unsafe fn install(frame: PhysFrame, physical_memory_offset: VirtAddr) -> Self {
+        let len = offset_of(&ap_trampoline_end) as usize;
+        assert!(len <= 4096, "AP trampoline does not fit in a frame");
+
+        let trampoline = Trampoline {
+            frame,
+            virt: physical_memory_offset + frame.start_address().as_u64(),
+        };
+        let code = &ap_trampoline_start as *const u8;
+        ptr::copy_nonoverlapping(code, trampoline.virt.as_mut_ptr(), len);
+
+        let base = frame.start_address().as_u64();
+        let gdt_base = base + offset_of(&ap_trampoline_gdtr) - 2 * 8;
+        trampoline.write::<u32>(&ap_trampoline_gdtr, 2, gdt_base as u32);
+        let long_mode = base + offset_of(&ap_trampoline_long);
+        trampoline.write::<u32>(&ap_trampoline_long_target, 0, long_mode as u32);
+
+        let (level_4_table, _) = Cr3::read();
+        trampoline.write(
+            &ap_trampoline_cr3,
+            0,
+            level_4_table.start_address().as_u64(),
+        );
+        // the APs run with the same extended features as the BSP
+        let efer = Efer::read() - EferFlags::LONG_MODE_ACTIVE;
+        trampoline.write(&ap_trampoline_efer, 0, efer.bits());
+        trampoline
+    }
+
+    /// Sets the stack, entry point and CPU number for the next AP to start.
+    pub fn prepare(&self, cpu_id: usize, stack_top: VirtAddr, entry: ApEntry) {
+        unsafe {
+            self.write(&ap_trampoline_stack, 0, stack_top.as_u64());
+            self.write(&ap_trampoline_entry, 0, entry as usize as u64);
+            self.write(&ap_trampoline_cpu_id, 0, cpu_id as u64);
+        }
+    }
+
+    /// The STARTUP IPI vector, i.e. the page number of the trampoline.
+    pub fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    unsafe fn write<T>(&self, symbol: &u8, extra_offset: u64, value: T) {
+        let target = self.virt + offset_of(symbol) + extra_offset;
+        // the GDTR base is not naturally aligned
+        ptr::write_unaligned(target.as_mut_ptr::<T>(), value);
+    }
+}
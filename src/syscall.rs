@@ -0,0 +1,271 @@
+use crate::{
+    gdt,
+    memory::{self, walker},
+    print, sprint, thread,
+};
+use core::arch::global_asm;
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+// System call numbers.
+//
+// The number is passed in `rax` and up to six arguments in `rdi`, `rsi`,
+// `rdx`, `r10`, `r8` and `r9`. The result comes back in `rax`; values
+// between -4095 and -1 are negated error codes (see `SyscallError`). All
+// other registers except `rcx` and `r11` are preserved.
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+
+/// File descriptors understood by `SYS_WRITE`.
+pub const FD_CONSOLE: u64 = 1;
+pub const FD_SERIAL: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// Bad file descriptor
+    BadFd = 9,
+    /// Pointer argument outside of user space, or not mapped for it
+    Fault = 14,
+    /// Invalid argument
+    Invalid = 22,
+    /// Unknown system call number
+    NoSys = 38,
+}
+
+impl SyscallError {
+    /// The value returned to user mode in `rax`.
+    pub fn as_return_value(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+type SyscallHandler = fn(u64, u64, u64, u64, u64, u64) -> SyscallResult;
+
+static SYSCALL_TABLE: [SyscallHandler; 4] = [sys_write, sys_exit, sys_yield, sys_sleep];
+
+/// Enables `syscall`/`sysret` on the calling CPU.
+///
+/// Must run on every CPU after its GDT and per-CPU data are set up.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.kernel_code_selector,
+        selectors.kernel_data_selector,
+    )
+    .expect("GDT layout does not match syscall/sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    // enter the kernel with interrupts disabled until the stack is switched
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
+}
+
+// The entry stub swaps to the kernel GS base, saves the user stack pointer
+// in the per-CPU area and continues on the kernel stack of the current
+// thread. There it saves the user return state and the argument registers
+// and calls `syscall_dispatch` with interrupts enabled.
+//
+// `enter_user_mode_asm` saves the callee-saved registers and irets to ring
+// 3; `exit_user_mode_asm` unwinds back to that frame from `SYS_EXIT`.
+//
+// Offsets into `PerCpu`: 8 = kernel stack top, 16 = user stack scratch,
+// 24 = user mode return stack, 32 = TSS.
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[16], rsp
+    mov rsp, gs:[8]
+
+    push qword ptr gs:[16]
+    push rcx
+    push r11
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+
+    // shift into the System V calling convention, the 6th argument goes on
+    // the stack (which also keeps it 16 byte aligned for the call)
+    push r9
+    mov r9, r8
+    mov r8, r10
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    sti
+    call syscall_dispatch
+    cli
+    add rsp, 8
+
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+
+.global enter_user_mode_asm
+enter_user_mode_asm:
+    pushfq
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov gs:[24], rsp
+    // system calls run below this frame, on a 16 byte aligned stack
+    mov rax, rsp
+    and rax, -16
+    mov gs:[8], rax
+    // and so do interrupts from ring 3; the TSS privilege stack is at 4
+    mov r8, gs:[32]
+    mov [r8 + 4], rax
+
+    push rdx
+    push rsi
+    push 0x202
+    push rcx
+    push rdi
+    swapgs
+    iretq
+
+.global exit_user_mode_asm
+exit_user_mode_asm:
+    mov rsp, gs:[24]
+    mov rax, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    popfq
+    ret
+"#
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn enter_user_mode_asm(entry: u64, stack_top: u64, user_ds: u64, user_cs: u64) -> u64;
+    fn exit_user_mode_asm(exit_code: u64) -> !;
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(
+    number: u64,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> u64 {
+    let result = match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(arg0, arg1, arg2, arg3, arg4, arg5),
+        None => Err(SyscallError::NoSys),
+    };
+    match result {
+        Ok(value) => value,
+        Err(err) => err.as_return_value(),
+    }
+}
+
+/// Runs user mode code at `entry` with the given stack until it calls
+/// `SYS_EXIT`, and returns the exit code.
+///
+/// System calls made by that code run on the current kernel stack, below
+/// this function's frame.
+///
+/// This function is unsafe because `entry` and `stack_top` must be mapped
+/// user accessible, and the per-CPU data of this CPU must be initialized.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> u64 {
+    let selectors = gdt::selectors();
+    enter_user_mode_asm(
+        entry.as_u64(),
+        stack_top.as_u64(),
+        u64::from(selectors.user_data_selector.0),
+        u64::from(selectors.user_code_selector.0),
+    )
+}
+
+/// Checks that `ptr..ptr + len` lies in the user half of the address space
+/// and that every page of it is mapped user accessible in the active page
+/// table, at every level of it, so that reading it cannot fault in ring 0.
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    let end = ptr.checked_add(len).ok_or(SyscallError::Fault)?;
+    if ptr < memory::USER_SPACE_START as u64 || end > memory::USER_SPACE_END as u64 {
+        return Err(SyscallError::Fault);
+    }
+    if len > 0 {
+        let physical_memory_offset = memory::physical_memory_offset();
+        let level_4_table = unsafe { memory::active_level_4_table(physical_memory_offset) };
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(ptr));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+        for page in Page::range_inclusive(first, last) {
+            let flags = unsafe {
+                walker::effective_flags(level_4_table, page.start_address(), physical_memory_offset)
+            };
+            let user_page = flags.map_or(false, |flags| {
+                flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+            });
+            if !user_page {
+                return Err(SyscallError::Fault);
+            }
+        }
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+/// `write(fd, buf, len)`: prints `len` bytes of UTF-8 text to the console
+/// or the serial port and returns the number of bytes written.
+fn sys_write(fd: u64, buf: u64, len: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    let bytes = user_slice(buf, len)?;
+    let text = core::str::from_utf8(bytes).map_err(|_| SyscallError::Invalid)?;
+    match fd {
+        FD_CONSOLE => print!("{}", text),
+        FD_SERIAL => sprint!("{}", text),
+        _ => return Err(SyscallError::BadFd),
+    }
+    Ok(len)
+}
+
+/// `exit(code)`: leaves user mode; `enter_user_mode` returns `code`.
+fn sys_exit(code: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    x86_64::instructions::interrupts::disable();
+    unsafe { exit_user_mode_asm(code) }
+}
+
+/// `yield()`: lets the other ready threads run first.
+fn sys_yield(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// `sleep(ms)`: blocks for at least `ms` milliseconds, rounded up to
+/// whole timer ticks.
+fn sys_sleep(ms: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    thread::sleep(ms);
+    Ok(0)
+}
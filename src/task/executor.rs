@@ -1,55 +1,293 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use super::{
+    join::JoinHandle,
+    spawner::{IrqSpawner, Spawner},
+    Priority, PriorityHandle, Task,
+};
+use crate::allocator::{try_arc, AllocError};
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    task::Wake,
+    vec::Vec,
+};
+use core::{
+    alloc::Layout,
+    future::Future,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// How often a ready task may be passed over for tasks of higher classes
+/// before it runs anyway.
+pub const AGING_LIMIT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The queue of an `IrqSpawner` has no free slot
+    QueueFull,
+    OutOfMemory(AllocError),
+}
+
+impl From<AllocError> for SpawnError {
+    fn from(err: AllocError) -> Self {
+        SpawnError::OutOfMemory(err)
+    }
+}
+
+/// Index of a task in the executor. Reused once the task has finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TaskId(usize);
+
+struct Entry {
+    task: Task,
+    /// Compared against woken wakers, which may outlive the task.
+    node: Arc<TaskWaker>,
+    waker: Waker,
+}
+
+enum Slot {
+    Task(Entry),
+    /// Links the free slots.
+    Free(Option<TaskId>),
+}
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    tasks: Vec<Slot>,
+    first_free: Option<TaskId>,
+    /// One FIFO queue of ready tasks per priority class. Each can hold all
+    /// tasks, so queueing a task never allocates.
+    ready: [VecDeque<TaskId>; Priority::COUNT],
+    /// Tasks woken since they were last moved into `ready`.
+    woken: Arc<WakeList>,
+    /// Per class, how often one of its tasks was passed over in a row.
+    passed_over: [usize; Priority::COUNT],
+    /// Queued spawns that were dropped because the heap was exhausted.
+    failed_spawns: usize,
+    spawner: Spawner,
+    irq_spawner: IrqSpawner,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            tasks: Vec::new(),
+            first_free: None,
+            ready: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            woken: Arc::new(WakeList::new()),
+            passed_over: [0; Priority::COUNT],
+            failed_spawns: 0,
+            spawner: Spawner::new(),
+            irq_spawner: IrqSpawner::new(),
+        }
+    }
+
+    /// An executor with room for `capacity` tasks; it grows beyond that
+    /// as needed.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut executor = Executor::new();
+        executor.tasks.reserve_exact(capacity);
+        for queue in executor.ready.iter_mut() {
+            queue.reserve_exact(capacity);
+        }
+        executor
+    }
+
+    /// How many tasks fit before the executor has to grow.
+    pub fn capacity(&self) -> usize {
+        self.tasks.capacity()
+    }
+
+    /// Number of tasks that have not finished yet.
+    pub fn len(&self) -> usize {
+        self.tasks
+            .iter()
+            .filter(|slot| matches!(slot, Slot::Task(_)))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of tasks from the spawners that were dropped because they
+    /// did not fit on the heap. Their `JoinHandle`s resolve to
+    /// `JoinError::Cancelled`.
+    pub fn failed_spawns(&self) -> usize {
+        self.failed_spawns
+    }
+
+    /// A handle for spawning tasks while the executor runs.
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    /// A handle for spawning tasks from interrupt handlers.
+    pub fn irq_spawner(&self) -> IrqSpawner {
+        self.irq_spawner.clone()
+    }
+
+    /// Queues `task` in the class it was created with, and returns a handle
+    /// to change that later.
+    pub fn spawn(&mut self, task: Task) -> PriorityHandle {
+        let priority = PriorityHandle::new(task.priority);
+        self.insert(task, priority.clone());
+        priority
+    }
+
+    fn insert(&mut self, task: Task, priority: PriorityHandle) {
+        if let Err(err) = self.try_insert(task, priority) {
+            panic!("cannot spawn task: {}", err);
+        }
+    }
+
+    /// Adds `task` and queues it, unless the heap is exhausted; all
+    /// allocations happen before anything changes.
+    fn try_insert(&mut self, task: Task, priority: PriorityHandle) -> Result<(), AllocError> {
+        self.try_reserve_slot()?;
+        let task_id = self.first_free.unwrap_or(TaskId(self.tasks.len()));
+        let node = try_arc(TaskWaker {
+            task_id,
+            priority,
+            queued: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+            woken: Arc::downgrade(&self.woken),
+        })?;
+
+        match self.first_free {
+            Some(_) => {
+                if let Slot::Free(next) = self.tasks[task_id.0] {
+                    self.first_free = next;
+                }
+            }
+            None => self.tasks.push(Slot::Free(None)),
+        }
+        self.ready[node.priority.get() as usize].push_back(task_id);
+        let waker = Waker::from(node.clone());
+        self.tasks[task_id.0] = Slot::Task(Entry { task, node, waker });
+        Ok(())
+    }
+
+    /// Makes sure that one more task fits, into the ready queues as well.
+    fn try_reserve_slot(&mut self) -> Result<(), AllocError> {
+        if self.first_free.is_none() {
+            let len = self.tasks.len() + 1;
+            self.tasks
+                .try_reserve(1)
+                .map_err(|_| out_of_memory::<Slot>(len))?;
+            for queue in self.ready.iter_mut() {
+                queue
+                    .try_reserve(len - queue.len())
+                    .map_err(|_| out_of_memory::<TaskId>(len))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, task_id: TaskId) {
+        self.tasks[task_id.0] = Slot::Free(self.first_free);
+        self.first_free = Some(task_id);
+    }
+
+    /// Spawns the tasks queued by the spawners. Nobody is there to take an
+    /// error, so a task that does not fit on the heap is dropped and
+    /// counted in `failed_spawns`.
+    fn spawn_queued(&mut self) {
+        while let Ok((task, priority)) = self.spawner.queue.pop() {
+            if self.try_insert(task, priority).is_err() {
+                self.failed_spawns += 1;
+            }
+        }
+        while let Ok(spawn) = self.irq_spawner.queue.pop() {
+            let task = (spawn.task)(spawn.arg);
+            let inserted = PriorityHandle::try_new(task.priority)
+                .and_then(|priority| self.try_insert(task, priority));
+            if inserted.is_err() {
+                self.failed_spawns += 1;
+            }
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if let Some(_) = self.tasks.insert(task_id, task) {
-            panic!("Task id already there in the Queue")
+    fn has_queued_spawns(&self) -> bool {
+        !self.spawner.queue.is_empty() || !self.irq_spawner.queue.is_empty()
+    }
+
+    /// Spawns `future` with normal priority, and returns a handle that
+    /// resolves to its output.
+    pub fn spawn_with_handle<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = Task::joinable(future, Priority::Normal);
+        self.spawn(task);
+        handle
+    }
+
+    /// Like `spawn(Task::new(future))`, but returns an error instead of
+    /// panicking if the task does not fit on the heap. The future is
+    /// dropped in that case.
+    pub fn try_spawn(
+        &mut self,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Result<PriorityHandle, SpawnError> {
+        let task = Task::try_new(future)?;
+        let priority = PriorityHandle::try_new(task.priority)?;
+        self.try_insert(task, priority.clone())?;
+        Ok(priority)
+    }
+
+    /// Moves the woken tasks into the ready queue of their current class.
+    fn queue_woken(&mut self) {
+        let tasks = &self.tasks;
+        let ready = &mut self.ready;
+        self.woken.drain(|node| match tasks.get(node.task_id.0) {
+            Some(Slot::Task(entry)) if Arc::ptr_eq(&entry.node, &node) => {
+                ready[node.priority.get() as usize].push_back(node.task_id);
+            }
+            // a leftover waker of a finished task
+            _ => {}
+        });
+    }
+
+    /// Takes the next task to poll: from the highest class with a ready
+    /// task, unless a lower class was passed over `AGING_LIMIT` times.
+    fn next_task(&mut self) -> Option<TaskId> {
+        let queues = &self.ready;
+        let aged = (0..Priority::COUNT)
+            .rev()
+            .find(|&class| self.passed_over[class] >= AGING_LIMIT && !queues[class].is_empty());
+        let class =
+            aged.or_else(|| (0..Priority::COUNT).find(|&class| !queues[class].is_empty()))?;
+        let task_id = self.ready[class].pop_front()?;
+
+        self.passed_over[class] = 0;
+        for lower in class + 1..Priority::COUNT {
+            if !self.ready[lower].is_empty() {
+                self.passed_over[lower] += 1;
+            }
         }
-        self.task_queue.push(task_id).expect("Task Queue full!");
+        Some(task_id)
     }
 
     pub fn run_ready_tasks(&mut self) {
-        // destructure `self` to avoid borrow checker errors
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
+        loop {
+            self.spawn_queued();
+            self.queue_woken();
+            let task_id = match self.next_task() {
+                Some(task_id) => task_id,
+                None => break,
+            };
+            let entry = match &mut self.tasks[task_id.0] {
+                Slot::Task(entry) => entry,
+                Slot::Free(_) => continue,
             };
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut ctx = Context::from_waker(waker);
-            match task.poll(&mut ctx) {
-                Poll::Ready(()) => {
-                    //clean up
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
+            // wakes from here on queue the task again
+            entry.node.queued.store(false, Ordering::SeqCst);
+            let mut ctx = Context::from_waker(&entry.waker);
+            match entry.task.poll(&mut ctx) {
+                Poll::Ready(()) => self.remove(task_id),
                 Poll::Pending => {}
             };
         }
@@ -65,7 +303,10 @@ impl Executor {
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        let idle = self.ready.iter().all(VecDeque::is_empty)
+            && self.woken.is_empty()
+            && !self.has_queued_spawns();
+        if idle {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -73,24 +314,33 @@ impl Executor {
     }
 }
 
+fn out_of_memory<T>(capacity: usize) -> AllocError {
+    Layout::array::<T>(capacity).map_or(AllocError::CapacityOverflow, AllocError::OutOfMemory)
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: PriorityHandle,
+    /// Set while the task is ready, so that more wakes are coalesced.
+    queued: AtomicBool,
+    /// Link in the `WakeList`.
+    next: AtomicPtr<TaskWaker>,
+    /// Weak, as the wakers may outlive the executor.
+    woken: Weak<WakeList>,
 }
-impl TaskWaker {
-    pub fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
-    }
 
-    pub fn wake_task(&self) {
-        self.task_queue
-            .push(self.task_id)
-            .expect("Task Queue full !!!");
+impl TaskWaker {
+    /// Neither allocates nor blocks, so it is safe in interrupt handlers.
+    fn wake_task(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(woken) = self.woken.upgrade() {
+            woken.push(self.clone());
+        }
     }
 }
+
 impl Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
         self.wake_task();
@@ -100,3 +350,60 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+/// The wakers of woken tasks, as a lock-free stack linked through the
+/// wakers themselves: pushing never fails and never allocates.
+struct WakeList {
+    head: AtomicPtr<TaskWaker>,
+}
+
+impl WakeList {
+    fn new() -> Self {
+        WakeList {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn push(&self, node: Arc<TaskWaker>) {
+        // the list owns the reference until `drain`
+        let node = Arc::into_raw(node) as *mut TaskWaker;
+        let mut head = self.head.load(Ordering::SeqCst);
+        loop {
+            unsafe { (*node).next.store(head, Ordering::SeqCst) };
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Takes all wakers, in the order they were pushed.
+    fn drain(&self, mut f: impl FnMut(Arc<TaskWaker>)) {
+        // only ever taken as a whole, so there is no ABA problem
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::SeqCst);
+        let mut reversed = ptr::null_mut();
+        while !node.is_null() {
+            let next = unsafe { (*node).next.swap(reversed, Ordering::SeqCst) };
+            reversed = node;
+            node = next;
+        }
+        while !reversed.is_null() {
+            let node = unsafe { Arc::from_raw(reversed) };
+            reversed = node.next.swap(ptr::null_mut(), Ordering::SeqCst);
+            f(node);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::SeqCst).is_null()
+    }
+}
+
+impl Drop for WakeList {
+    fn drop(&mut self) {
+        self.drain(drop);
+    }
+}
@@ -0,0 +1,166 @@
+//! Handles to the output of spawned tasks.
+//!
+//! A panic cannot be turned into an error value here: the kernel is built
+//! with `panic = "abort"` and its panic handler never returns. A task that
+//! ends without an output in any other way is reported as a `JoinError`.
+
+use super::{Priority, Task};
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// `JoinHandle::abort` stopped the task
+    Aborted,
+    /// The task was dropped before it finished, e.g. with its executor
+    Cancelled,
+}
+
+struct JoinState<T> {
+    result: Option<Result<T, JoinError>>,
+    /// Set once the handle took the result.
+    taken: bool,
+    aborted: bool,
+    /// Wakes whoever awaits the handle.
+    join_waker: Option<Waker>,
+    /// Wakes the task, so that it notices being aborted.
+    task_waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    fn finish(&mut self, result: Result<T, JoinError>) {
+        self.result = Some(result);
+        if let Some(waker) = self.join_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.result.is_some() || self.taken
+    }
+}
+
+/// Runs the future of a joinable task and hands its output to the handle.
+struct Joinable<F: Future> {
+    /// `None` once it finished or was aborted
+    future: Option<F>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // `future` is never moved out; it is only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        {
+            let mut state = this.state.lock();
+            if state.aborted {
+                this.future = None;
+                state.finish(Err(JoinError::Aborted));
+                return Poll::Ready(());
+            }
+            match &state.task_waker {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                _ => state.task_waker = Some(cx.waker().clone()),
+            }
+        }
+
+        let future = match this.future.as_mut() {
+            Some(future) => unsafe { Pin::new_unchecked(future) },
+            None => return Poll::Ready(()),
+        };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                this.future = None;
+                this.state.lock().finish(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if !state.is_finished() {
+            state.finish(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// Resolves to the output of a task.
+///
+/// Dropping the handle detaches the task, which keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Stops the task the next time the executor would poll it, dropping
+    /// its future. The handle then resolves to `JoinError::Aborted`,
+    /// unless the task has finished already.
+    pub fn abort(&self) {
+        let mut state = self.state.lock();
+        if state.is_finished() {
+            return;
+        }
+        state.aborted = true;
+        if let Some(waker) = state.task_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Lets the task run on without anyone waiting for its output.
+    pub fn detach(self) {}
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().is_finished()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(result) = state.result.take() {
+            state.taken = true;
+            return Poll::Ready(result);
+        }
+        assert!(!state.taken, "JoinHandle polled after it resolved");
+        state.join_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Task {
+    /// A task running `future`, and a handle to its output.
+    pub fn joinable<F>(future: F, priority: Priority) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState {
+            result: None,
+            taken: false,
+            aborted: false,
+            join_waker: None,
+            task_waker: None,
+        }));
+        let task = Task::with_priority(
+            Joinable {
+                future: Some(future),
+                state: state.clone(),
+            },
+            priority,
+        );
+        (task, JoinHandle { state })
+    }
+}
@@ -1,39 +1,126 @@
 pub mod executor;
+pub mod join;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod spawner;
+pub mod sync;
 
-use alloc::boxed::Box;
+use crate::allocator::{try_arc, try_box, AllocError};
+use alloc::{boxed::Box, sync::Arc};
 use core::{
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicU8, Ordering},
     task::{Context, Poll},
 };
 
+/// Scheduling class of a task in the `Executor`.
+///
+/// Ready tasks of a higher class run first; see `executor::AGING_LIMIT` for
+/// how lower classes still get their turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// Number of classes
+    pub const COUNT: usize = 3;
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Priority::High,
+            1 => Priority::Normal,
+            _ => Priority::Low,
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// Changes the priority of a spawned task, from anywhere.
+///
+/// A task that is already queued keeps its place; the new priority counts
+/// from its next wakeup on.
+#[derive(Debug, Clone)]
+pub struct PriorityHandle(Arc<AtomicU8>);
+
+impl PriorityHandle {
+    fn new(priority: Priority) -> Self {
+        PriorityHandle(Arc::new(AtomicU8::new(priority as u8)))
+    }
+
+    fn try_new(priority: Priority) -> Result<Self, AllocError> {
+        Ok(PriorityHandle(try_arc(AtomicU8::new(priority as u8))?))
+    }
+
+    pub fn get(&self) -> Priority {
+        Priority::from_u8(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, priority: Priority) {
+        self.0.store(priority as u8, Ordering::Relaxed);
+    }
+}
+
 pub struct Task {
-    id: TaskId, // new
+    priority: Priority,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
     pub fn new(f: impl Future<Output = ()> + 'static) -> Self {
+        Task::with_priority(f, Priority::Normal)
+    }
+
+    pub fn with_priority(f: impl Future<Output = ()> + 'static, priority: Priority) -> Self {
         Task {
-            id: TaskId::new(),
+            priority,
             future: Box::pin(f),
         }
     }
 
+    /// Like `new`, but returns an error if the heap is exhausted.
+    pub fn try_new(f: impl Future<Output = ()> + 'static) -> Result<Self, AllocError> {
+        let future: Box<dyn Future<Output = ()>> = try_box(f)?;
+        Ok(Task {
+            priority: Priority::Normal,
+            future: Box::into_pin(future),
+        })
+    }
+
     pub fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+/// Lets the other ready tasks run before the calling task goes on.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
 
-static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-impl TaskId {
-    fn new() -> Self {
-        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+/// Future returned by `yield_now`: wakes itself and is ready on the next
+/// poll.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 }
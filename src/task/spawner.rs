@@ -0,0 +1,91 @@
+use super::{executor::SpawnError, join::JoinHandle, Priority, PriorityHandle, Task};
+use alloc::sync::Arc;
+use core::future::Future;
+use crossbeam_queue::{ArrayQueue, SegQueue};
+
+/// How many spawns from interrupt handlers can wait for the executor.
+pub const IRQ_SPAWN_QUEUE_SIZE: usize = 32;
+
+/// Spawns tasks on an `Executor` without borrowing it, e.g. from one of
+/// its tasks. The tasks start the next time the executor looks for ready
+/// tasks.
+///
+/// Tasks are not `Send`, so neither is this; interrupt handlers use an
+/// `IrqSpawner` instead.
+#[derive(Clone)]
+pub struct Spawner {
+    pub(super) queue: Arc<SegQueue<(Task, PriorityHandle)>>,
+}
+
+impl Spawner {
+    pub(super) fn new() -> Self {
+        Spawner {
+            queue: Arc::new(SegQueue::new()),
+        }
+    }
+
+    /// Like `Executor::spawn`.
+    pub fn spawn(&self, task: Task) -> PriorityHandle {
+        let priority = PriorityHandle::new(task.priority);
+        self.queue.push((task, priority.clone()));
+        priority
+    }
+
+    /// Like `Executor::spawn_with_handle`.
+    pub fn spawn_with_handle<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (task, handle) = Task::joinable(future, Priority::Normal);
+        self.spawn(task);
+        handle
+    }
+
+    /// Like `Executor::try_spawn`. The executor may still run out of
+    /// memory when it takes the task from the queue; it then drops the
+    /// task and counts it in `Executor::failed_spawns`.
+    pub fn try_spawn(
+        &self,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Result<PriorityHandle, SpawnError> {
+        let task = Task::try_new(future)?;
+        let priority = PriorityHandle::try_new(task.priority)?;
+        self.queue.push((task, priority.clone()));
+        Ok(priority)
+    }
+}
+
+/// A spawn requested by an interrupt handler: the executor calls `task`
+/// with `arg` to create the task.
+pub(super) struct IrqSpawn {
+    pub task: fn(u64) -> Task,
+    pub arg: u64,
+}
+
+/// Spawns tasks from interrupt handlers.
+///
+/// Interrupt handlers may neither allocate nor block, so this only queues
+/// a function that creates the task, in a queue of fixed size; the
+/// executor calls it later.
+#[derive(Clone)]
+pub struct IrqSpawner {
+    pub(super) queue: Arc<ArrayQueue<IrqSpawn>>,
+}
+
+impl IrqSpawner {
+    pub(super) fn new() -> Self {
+        IrqSpawner {
+            queue: Arc::new(ArrayQueue::new(IRQ_SPAWN_QUEUE_SIZE)),
+        }
+    }
+
+    /// Queues `task(arg)` for the executor to spawn. Fails with
+    /// `SpawnError::QueueFull` if `IRQ_SPAWN_QUEUE_SIZE` spawns are
+    /// waiting already.
+    pub fn spawn(&self, task: fn(u64) -> Task, arg: u64) -> Result<(), SpawnError> {
+        self.queue
+            .push(IrqSpawn { task, arg })
+            .map_err(|_| SpawnError::QueueFull)
+    }
+}
@@ -0,0 +1,18 @@
+//! Synchronization between tasks.
+//!
+//! Waiting tasks register their `Waker` and are woken when they can make
+//! progress, so nothing here depends on a particular executor. The
+//! internal spinlocks are only held briefly, but must not be taken from
+//! interrupt handlers.
+
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod rwlock;
+mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard, MAX_READERS};
+pub use semaphore::{Acquire, AcquireError, Semaphore, SemaphorePermit, TryAcquireError};
@@ -0,0 +1,256 @@
+//! Channels with any number of senders and one receiver, bounded or not.
+
+use super::semaphore::{Semaphore, TryAcquireError};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::{future::poll_fn, Stream};
+use spin::Mutex;
+
+/// The receiver is gone; the value is handed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// A bounded channel has no free slot
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Empty, and all senders are gone or the receiver closed
+    Disconnected,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    /// Set by the receiver; no more values are accepted.
+    closed: bool,
+    receiver_waker: Option<Waker>,
+}
+
+struct Chan<T> {
+    state: Mutex<State<T>>,
+    /// Free slots of a bounded channel.
+    slots: Option<Semaphore>,
+}
+
+impl<T> Chan<T> {
+    fn new(slots: Option<Semaphore>) -> Arc<Self> {
+        Arc::new(Chan {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                senders: 1,
+                closed: false,
+                receiver_waker: None,
+            }),
+            slots,
+        })
+    }
+
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(value);
+        }
+        state.queue.push_back(value);
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    fn add_sender(&self) {
+        self.state.lock().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let mut state = self.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.receiver_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A channel holding at most `capacity` values; `Sender::send` waits while
+/// it is full.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let chan = Chan::new(Some(Semaphore::new(capacity)));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// A channel that grows as needed; sending never waits.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    fn slots(&self) -> &Semaphore {
+        self.chan.slots.as_ref().unwrap()
+    }
+
+    /// Waits for a free slot and queues `value`.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let permit = match self.slots().acquire().await {
+            Ok(permit) => permit,
+            Err(_) => return Err(SendError(value)),
+        };
+        self.chan.push(value).map_err(SendError)?;
+        // the receiver frees the slot again
+        permit.forget();
+        Ok(())
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let permit = match self.slots().try_acquire() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+        };
+        self.chan.push(value).map_err(TrySendError::Closed)?;
+        permit.forget();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.chan.push(value).map_err(SendError)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        UnboundedSender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// Receives the values in the order they were sent. Also a `Stream` of
+/// them.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The next value, or `None` once the channel is empty and all
+    /// senders are gone or `close` was called.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.chan.state.lock();
+        match state.queue.pop_front() {
+            Some(value) => {
+                drop(state);
+                self.free_slot();
+                Ok(value)
+            }
+            None if state.senders == 0 || state.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.chan.state.lock();
+        match state.queue.pop_front() {
+            Some(value) => {
+                drop(state);
+                self.free_slot();
+                Poll::Ready(Some(value))
+            }
+            None if state.senders == 0 || state.closed => Poll::Ready(None),
+            None => {
+                state.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn free_slot(&self) {
+        if let Some(slots) = &self.chan.slots {
+            slots.add_permits(1);
+        }
+    }
+
+    /// Makes all further sends fail, and wakes the senders waiting for a
+    /// slot. The values queued already can still be received.
+    pub fn close(&mut self) {
+        self.chan.state.lock().closed = true;
+        if let Some(slots) = &self.chan.slots {
+            slots.close();
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+        // the senders may hold on to the channel for a while
+        let queued = core::mem::take(&mut self.chan.state.lock().queue);
+        drop(queued);
+    }
+}
@@ -0,0 +1,162 @@
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Waiting,
+    /// Chosen by `notify_one`
+    NotifiedOne,
+    NotifiedAll,
+}
+
+struct Waiter {
+    status: Status,
+    waker: Waker,
+}
+
+struct State {
+    /// Set by a `notify_one` that found no waiter.
+    permit: bool,
+    /// In arrival order, by key.
+    waiters: BTreeMap<u64, Waiter>,
+    next_key: u64,
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        let waiter = self
+            .waiters
+            .values_mut()
+            .find(|waiter| waiter.status == Status::Waiting);
+        match waiter {
+            Some(waiter) => {
+                waiter.status = Status::NotifiedOne;
+                waiter.waker.wake_by_ref();
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+/// Wakes waiting tasks on demand, like an event.
+///
+/// `notify_one` is remembered if nobody waits yet, so a task checking a
+/// condition and then awaiting `notified` does not miss a notification
+/// sent in between.
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: Mutex::new(State {
+                permit: false,
+                waiters: BTreeMap::new(),
+                next_key: 0,
+            }),
+        }
+    }
+
+    /// Waits for a notification. Only counts as waiting from its first
+    /// poll on.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            key: None,
+        }
+    }
+
+    /// Wakes the longest waiting task, or the next one to call `notified`
+    /// if none waits. Several calls without a waiter count once.
+    pub fn notify_one(&self) {
+        self.state.lock().notify_one();
+    }
+
+    /// Wakes all tasks waiting right now. Unlike `notify_one`, this is
+    /// not remembered.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        for waiter in state.waiters.values_mut() {
+            if waiter.status == Status::Waiting {
+                waiter.status = Status::NotifiedAll;
+                waiter.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+/// Future returned by `Notify::notified`.
+///
+/// Dropping it after `notify_one` chose it passes the notification on.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Set while queued as a waiter.
+    key: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let notify = self.notify;
+        let mut state = notify.state.lock();
+        let key = match self.key {
+            Some(key) => key,
+            None => {
+                if state.permit {
+                    state.permit = false;
+                    return Poll::Ready(());
+                }
+                let key = state.next_key;
+                state.next_key += 1;
+                state.waiters.insert(
+                    key,
+                    Waiter {
+                        status: Status::Waiting,
+                        waker: cx.waker().clone(),
+                    },
+                );
+                self.key = Some(key);
+                return Poll::Pending;
+            }
+        };
+
+        let waiter = state.waiters.get_mut(&key).unwrap();
+        if waiter.status == Status::Waiting {
+            if !waiter.waker.will_wake(cx.waker()) {
+                waiter.waker = cx.waker().clone();
+            }
+            return Poll::Pending;
+        }
+        state.waiters.remove(&key);
+        self.key = None;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        let mut state = self.notify.state.lock();
+        if let Some(waiter) = state.waiters.remove(&key) {
+            if waiter.status == Status::NotifiedOne {
+                state.notify_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! A channel for sending a single value.
+
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+/// The `Sender` was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing was sent yet
+    Empty,
+    /// The `Sender` was dropped without sending, or the value was taken
+    Closed,
+}
+
+struct State<T> {
+    value: Option<T>,
+    /// Set once the sender sent or was dropped.
+    sender_done: bool,
+    receiver_dropped: bool,
+    receiver_waker: Option<Waker>,
+    /// Wakes a task waiting in `Sender::closed`.
+    sender_waker: Option<Waker>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        value: None,
+        sender_done: false,
+        receiver_dropped: false,
+        receiver_waker: None,
+        sender_waker: None,
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Hands `value` to the receiver, or back if the receiver was dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut state = self.state.lock();
+        if state.receiver_dropped {
+            return Err(value);
+        }
+        state.value = Some(value);
+        Ok(())
+        // dropping `self` wakes the receiver
+    }
+
+    /// Whether the receiver was dropped, so that sending is pointless.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().receiver_dropped
+    }
+
+    /// Waits until the receiver is dropped.
+    pub async fn closed(&mut self) {
+        poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if state.receiver_dropped {
+                return Poll::Ready(());
+            }
+            state.sender_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.sender_done = true;
+        if let Some(waker) = state.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves to the sent value.
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.state.lock();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_done => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Makes a later `send` fail. A value sent before stays receivable.
+    pub fn close(&mut self) {
+        let mut state = self.state.lock();
+        state.receiver_dropped = true;
+        if let Some(waker) = state.sender_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if state.sender_done {
+            return Poll::Ready(Err(RecvError));
+        }
+        state.receiver_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,236 @@
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+/// The semaphore was closed while waiting, see `Semaphore::close`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    /// Not enough permits are available, or others wait for them already
+    NoPermits,
+    Closed,
+}
+
+struct Waiter {
+    needed: usize,
+    /// Set once the permits are taken out for this waiter.
+    granted: bool,
+    waker: Waker,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    /// In arrival order, by key.
+    waiters: BTreeMap<u64, Waiter>,
+    next_key: u64,
+}
+
+impl State {
+    /// Hands the available permits to the waiters in order, as long as
+    /// they suffice, and wakes them.
+    fn grant(&mut self) {
+        let permits = &mut self.permits;
+        for waiter in self.waiters.values_mut().filter(|waiter| !waiter.granted) {
+            if waiter.needed > *permits {
+                break;
+            }
+            *permits -= waiter.needed;
+            waiter.granted = true;
+            waiter.waker.wake_by_ref();
+        }
+    }
+}
+
+/// A counting semaphore for tasks.
+///
+/// Waiters are served in order: a task asking for more permits than are
+/// available holds back all that come after it, even smaller requests.
+/// This is what keeps writers of a `RwLock` from starving.
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                closed: false,
+                waiters: BTreeMap::new(),
+                next_key: 0,
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Waits for one permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits for `n` permits, which are handed out together.
+    pub fn acquire_many(&self, n: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: n,
+            key: None,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(TryAcquireError::Closed);
+        }
+        if !state.waiters.is_empty() || state.permits < n {
+            return Err(TryAcquireError::NoPermits);
+        }
+        state.permits -= n;
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    /// Returns `n` permits, or adds new ones, and wakes the waiters they
+    /// now suffice for.
+    pub fn add_permits(&self, n: usize) {
+        let mut state = self.state.lock();
+        state.permits += n;
+        state.grant();
+    }
+
+    /// Fails all waiting and future acquisitions. Permits that were
+    /// handed out stay valid.
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        for waiter in state.waiters.values().filter(|waiter| !waiter.granted) {
+            waiter.waker.wake_by_ref();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+/// Future returned by `Semaphore::acquire` and `acquire_many`.
+///
+/// Dropping it gives up its place in the queue, and returns the permits if
+/// they were granted already.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    /// Set while queued as a waiter.
+    key: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let needed = self.needed;
+        // only built once the permits are taken, as it returns them on drop
+        let permit = || SemaphorePermit {
+            semaphore,
+            permits: needed,
+        };
+        let mut state = semaphore.state.lock();
+        let key = match self.key {
+            Some(key) => key,
+            None => {
+                if state.closed {
+                    return Poll::Ready(Err(AcquireError));
+                }
+                if state.waiters.is_empty() && state.permits >= self.needed {
+                    state.permits -= self.needed;
+                    return Poll::Ready(Ok(permit()));
+                }
+                let key = state.next_key;
+                state.next_key += 1;
+                state.waiters.insert(
+                    key,
+                    Waiter {
+                        needed: self.needed,
+                        granted: false,
+                        waker: cx.waker().clone(),
+                    },
+                );
+                self.key = Some(key);
+                return Poll::Pending;
+            }
+        };
+
+        let closed = state.closed;
+        let waiter = state.waiters.get_mut(&key).unwrap();
+        if !waiter.granted && !closed {
+            if !waiter.waker.will_wake(cx.waker()) {
+                waiter.waker = cx.waker().clone();
+            }
+            return Poll::Pending;
+        }
+        let waiter = state.waiters.remove(&key).unwrap();
+        self.key = None;
+        if waiter.granted {
+            Poll::Ready(Ok(permit()))
+        } else {
+            Poll::Ready(Err(AcquireError))
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        let mut state = self.semaphore.state.lock();
+        if let Some(waiter) = state.waiters.remove(&key) {
+            if waiter.granted {
+                state.permits += waiter.needed;
+            }
+        }
+        // it may have held back the waiters after it
+        state.grant();
+    }
+}
+
+/// Permits taken from a `Semaphore`, returned on drop.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits from being returned.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+
+    pub fn num_permits(&self) -> usize {
+        self.permits
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
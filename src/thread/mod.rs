@@ -0,0 +1,219 @@
+//! Preemptive kernel threads.
+//!
+//! Threads only run on the bootstrap processor, the only CPU that gets
+//! timer interrupts. Every tick ends the time slice of the running thread
+//! if another one is ready, in round-robin order.
+
+mod scheduler;
+mod stack;
+
+pub use stack::MAX_THREADS;
+
+use crate::{
+    allocator::{try_box, AllocError},
+    interrupts,
+    memory::vma::{VmaError, VmaManager},
+};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use scheduler::{State, Thread, SCHEDULER};
+use stack::Stack;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{mapper::MapToError, Size4KiB},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl ThreadId {
+    fn new() -> Self {
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum ThreadError {
+    /// `init` or `memory::init_global_frame_allocator` was not called
+    NotInitialized,
+    /// All `MAX_THREADS` stacks are in use
+    TooManyThreads,
+    /// The area for thread stacks could not be reserved
+    Reserve(VmaError),
+    Map(MapToError<Size4KiB>),
+    OutOfMemory(AllocError),
+}
+
+impl From<AllocError> for ThreadError {
+    fn from(err: AllocError) -> Self {
+        ThreadError::OutOfMemory(err)
+    }
+}
+
+/// Turns the caller into the boot thread and starts scheduling.
+///
+/// Must be called once, after `memory::init_global_frame_allocator`; the
+/// timer interrupt does the rest.
+pub fn init(vmas: &mut VmaManager) -> Result<(), ThreadError> {
+    stack::reserve(vmas).map_err(ThreadError::Reserve)?;
+    let idle = Thread::new("idle", Stack::new()?, Box::new(idle));
+    let scheduler = scheduler::Scheduler::new(Thread::boot(), idle);
+    without_interrupts(|| {
+        let mut slot = SCHEDULER.lock();
+        assert!(slot.is_none(), "thread::init called twice");
+        *slot = Some(scheduler);
+    });
+    Ok(())
+}
+
+fn idle() {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Starts a thread running `f` on a stack of its own.
+pub fn spawn_thread<F>(name: &'static str, f: F) -> Result<ThreadId, ThreadError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let entry: Box<dyn FnOnce() + Send> = try_box(f)?;
+    let thread = Thread::new(name, Stack::new()?, entry);
+    let id = thread.id;
+    without_interrupts(|| match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => {
+            scheduler.add(thread);
+            Ok(id)
+        }
+        None => Err(ThreadError::NotInitialized),
+    })
+}
+
+/// The thread calling this, or `None` before `init`.
+pub fn current() -> Option<ThreadId> {
+    without_interrupts(|| SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current))
+}
+
+/// The name `thread` was spawned with, if it still exists.
+pub fn name(thread: ThreadId) -> Option<&'static str> {
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler
+            .as_ref()?
+            .threads
+            .get(&thread)
+            .map(|thread| thread.name)
+    })
+}
+
+/// Lets the other ready threads run first. Only spins once before `init`.
+pub fn yield_now() {
+    let yielded = block_current(|scheduler| {
+        if !scheduler.has_ready() {
+            return false;
+        }
+        let current = scheduler.current;
+        scheduler.make_ready(current);
+        true
+    });
+    if !yielded {
+        core::hint::spin_loop();
+    }
+}
+
+/// Blocks for at least `ms` milliseconds, rounded up to whole timer ticks.
+pub fn sleep(ms: u64) {
+    sleep_ticks(interrupts::ms_to_ticks(ms));
+}
+
+/// Blocks until at least `n` more timer ticks have elapsed. Halts the CPU
+/// meanwhile before `init`.
+pub fn sleep_ticks(n: u64) {
+    let until = interrupts::ticks() + n;
+    let blocked = block_current(|scheduler| {
+        scheduler.current_mut().state = State::Sleeping(until);
+        true
+    });
+    if !blocked {
+        interrupts::wait_ticks(n);
+    }
+}
+
+/// Blocks until `thread` has finished. Returns at once if it does not
+/// exist (any more).
+pub fn join(thread: ThreadId) {
+    assert!(current() != Some(thread), "thread cannot join itself");
+    block_current(|scheduler| {
+        let current = scheduler.current;
+        match scheduler.threads.get_mut(&thread) {
+            Some(other) if other.state != State::Finished => {
+                other.joiners.push(current);
+                scheduler.current_mut().state = State::Joining;
+                true
+            }
+            _ => false,
+        }
+    });
+}
+
+/// Ends the calling thread. Threads also end when their function returns.
+pub fn exit() -> ! {
+    let switched = block_current(|scheduler| {
+        let current = scheduler.current_mut();
+        current.state = State::Finished;
+        for joiner in core::mem::take(&mut current.joiners) {
+            scheduler.make_ready(joiner);
+        }
+        true
+    });
+    assert!(switched, "thread::exit called before thread::init");
+    unreachable!("finished thread was switched back to");
+}
+
+/// Runs `block` on the scheduler, and switches to another thread if it
+/// returns true, which means it took the current thread out of the
+/// `Running` state. Returns once this thread runs again, and whether it
+/// switched; never before `init`.
+fn block_current(block: impl FnOnce(&mut scheduler::Scheduler) -> bool) -> bool {
+    without_interrupts(|| {
+        let switch = match SCHEDULER.lock().as_mut() {
+            Some(scheduler) => {
+                if !block(scheduler) {
+                    return false;
+                }
+                scheduler.next()
+            }
+            None => return false,
+        };
+        if let Some(switch) = switch {
+            unsafe { scheduler::switch(switch) };
+        }
+        true
+    })
+}
+
+/// Called by the timer interrupt handler, after the end of interrupt is
+/// signalled: wakes sleeping threads and ends the current time slice.
+pub fn preempt() {
+    let switch = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => {
+            scheduler.wake_sleepers(interrupts::ticks());
+            if !scheduler.has_ready() {
+                return;
+            }
+            let current = scheduler.current;
+            scheduler.make_ready(current);
+            scheduler.next()
+        }
+        None => return,
+    };
+    if let Some(switch) = switch {
+        unsafe { scheduler::switch(switch) };
+    }
+}
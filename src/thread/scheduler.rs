@@ -0,0 +1,259 @@
+use super::{stack::Stack, ThreadId};
+use crate::smp::percpu;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::arch::global_asm;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Until the given timer tick
+    Sleeping(u64),
+    /// Until another thread finishes
+    Joining,
+    /// Freed as soon as another thread runs
+    Finished,
+}
+
+pub struct Thread {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    /// Saved stack pointer while the thread does not run.
+    rsp: u64,
+    // per-CPU state of the thread, swapped in and out with it
+    kernel_stack_top: u64,
+    user_mode_return: u64,
+    privilege_stack_top: u64,
+    /// `None` for the boot thread, which runs on the boot stack. Only
+    /// kept to be unmapped with the thread.
+    #[allow(dead_code)]
+    stack: Option<Stack>,
+    /// The function the thread runs, until it starts.
+    pub entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Threads waiting in `join` for this one.
+    pub joiners: Vec<ThreadId>,
+}
+
+impl Thread {
+    /// The thread that called `thread::init`; its context is saved when
+    /// it is first switched away from.
+    pub fn boot() -> Self {
+        Thread {
+            id: ThreadId::new(),
+            name: "boot",
+            state: State::Running,
+            rsp: 0,
+            kernel_stack_top: 0,
+            user_mode_return: 0,
+            privilege_stack_top: 0,
+            stack: None,
+            entry: None,
+            joiners: Vec::new(),
+        }
+    }
+
+    /// A thread that starts in `thread_start`, with interrupts disabled.
+    pub fn new(name: &'static str, stack: Stack, entry: Box<dyn FnOnce() + Send>) -> Self {
+        let top = stack.top();
+        // the frame `switch_context` pops: r15, r14, r13, r12, rbp, rbx,
+        // rflags (only the reserved bit set) and the return address, below
+        // a null return address for `thread_start`; `ret` leaves the stack
+        // aligned like a `call` would
+        let frame: [u64; 9] = [0, 0, 0, 0, 0, 0, 0x2, thread_start as usize as u64, 0];
+        let rsp = top - 8 * frame.len() as u64;
+        unsafe { rsp.as_mut_ptr::<[u64; 9]>().write(frame) };
+        Thread {
+            id: ThreadId::new(),
+            name,
+            state: State::Ready,
+            rsp: rsp.as_u64(),
+            kernel_stack_top: top.as_u64(),
+            user_mode_return: 0,
+            privilege_stack_top: top.as_u64(),
+            stack: Some(stack),
+            entry: Some(entry),
+            joiners: Vec::new(),
+        }
+    }
+}
+
+/// The run queue and all threads.
+///
+/// Only used with interrupts disabled, so that the timer interrupt can
+/// never find the lock taken.
+pub struct Scheduler {
+    /// Boxed, so that the saved stack pointers stay put.
+    pub threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    pub current: ThreadId,
+    /// Runs when no other thread is ready; never queued.
+    idle: ThreadId,
+}
+
+pub static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// Stack pointers for `switch_context`.
+pub struct Switch {
+    old_rsp: *mut u64,
+    new_rsp: u64,
+}
+
+impl Scheduler {
+    pub fn new(boot: Thread, idle: Thread) -> Self {
+        let mut scheduler = Scheduler {
+            threads: BTreeMap::new(),
+            ready: VecDeque::with_capacity(super::stack::MAX_THREADS),
+            current: boot.id,
+            idle: idle.id,
+        };
+        scheduler.threads.insert(boot.id, Box::new(boot));
+        scheduler.threads.insert(idle.id, Box::new(idle));
+        scheduler
+    }
+
+    pub fn current_mut(&mut self) -> &mut Thread {
+        self.threads.get_mut(&self.current).unwrap()
+    }
+
+    pub fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    /// Queues a thread that is not queued yet.
+    pub fn make_ready(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.state = State::Ready;
+            if id != self.idle {
+                self.ready.push_back(id);
+            }
+        }
+    }
+
+    pub fn add(&mut self, thread: Thread) {
+        let id = thread.id;
+        self.threads.insert(id, Box::new(thread));
+        self.make_ready(id);
+    }
+
+    /// Wakes the threads whose sleep ends at or before `tick`.
+    pub fn wake_sleepers(&mut self, tick: u64) {
+        let ready = &mut self.ready;
+        for thread in self.threads.values_mut() {
+            if let State::Sleeping(until) = thread.state {
+                if until <= tick {
+                    thread.state = State::Ready;
+                    ready.push_back(thread.id);
+                }
+            }
+        }
+    }
+
+    /// Makes the next ready thread, or the idle thread if there is none,
+    /// the current one. The caller has already moved the current thread
+    /// out of the `Running` state, or queued it.
+    ///
+    /// Returns what to pass to `switch`, after the lock is released.
+    pub fn next(&mut self) -> Option<Switch> {
+        let next = self.ready.pop_front().unwrap_or(self.idle);
+        if next == self.current {
+            self.current_mut().state = State::Running;
+            return None;
+        }
+
+        let percpu = percpu::try_current();
+        let old = self.current_mut();
+        if let Some(percpu) = percpu {
+            old.kernel_stack_top = percpu.kernel_stack_top().as_u64();
+            old.user_mode_return = percpu.user_mode_return();
+            old.privilege_stack_top = percpu.privilege_stack_top().as_u64();
+        }
+        let old_rsp: *mut u64 = &mut old.rsp;
+
+        self.current = next;
+        let new = self.current_mut();
+        new.state = State::Running;
+        if let Some(percpu) = percpu {
+            percpu.set_kernel_stack_top(VirtAddr::new(new.kernel_stack_top));
+            percpu.set_user_mode_return(new.user_mode_return);
+            percpu.set_privilege_stack_top(VirtAddr::new(new.privilege_stack_top));
+        }
+        Some(Switch {
+            old_rsp,
+            new_rsp: new.rsp,
+        })
+    }
+
+    /// Frees the finished threads, which do not run any more.
+    fn reap(&mut self) {
+        let current = self.current;
+        self.threads
+            .retain(|&id, thread| id == current || thread.state != State::Finished);
+    }
+}
+
+/// Continues with the thread chosen by `Scheduler::next`.
+///
+/// Must be called with interrupts disabled and the scheduler unlocked.
+/// Returns when the calling thread is switched back to.
+pub unsafe fn switch(switch: Switch) {
+    switch_context(switch.old_rsp, switch.new_rsp);
+    after_switch();
+}
+
+/// Runs on the new thread after every switch.
+fn after_switch() {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.reap();
+    }
+}
+
+/// First code of every thread, reached through `switch_context`.
+extern "C" fn thread_start() -> ! {
+    after_switch();
+    let entry = SCHEDULER
+        .lock()
+        .as_mut()
+        .and_then(|scheduler| scheduler.current_mut().entry.take())
+        .expect("thread started without an entry point");
+    x86_64::instructions::interrupts::enable();
+    entry();
+    super::exit();
+}
+
+// Saves the callee-saved registers and the flags on the current stack,
+// stores the stack pointer in `*rdi` and restores the same from the stack
+// at `rsi`. Everything else is saved by the caller.
+global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    pushfq
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    popfq
+    ret
+"#
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
@@ -0,0 +1,123 @@
+use super::ThreadError;
+use crate::memory::{
+    self,
+    vma::{VmaError, VmaManager},
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// Most threads that can exist at the same time, including the boot and
+/// the idle thread.
+pub const MAX_THREADS: usize = 256;
+
+/// Every stack sits above an unmapped guard page, so that an overflow
+/// faults instead of running into the stack below.
+const STACK_SIZE: u64 = 16 * 4096;
+const STACK_STRIDE: u64 = 4096 + STACK_SIZE;
+
+const STACK_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Start of the area holding all thread stacks.
+static STACKS_START: AtomicU64 = AtomicU64::new(0);
+/// One bit per stack slot that is in use.
+static USED_SLOTS: Mutex<[u64; MAX_THREADS / 64]> = Mutex::new([0; MAX_THREADS / 64]);
+
+/// Reserves the addresses of all thread stacks; they are mapped on demand.
+pub fn reserve(vmas: &mut VmaManager) -> Result<(), VmaError> {
+    let start = vmas.reserve(MAX_THREADS as u64 * STACK_STRIDE, "thread stacks")?;
+    STACKS_START.store(start.as_u64(), Ordering::SeqCst);
+    Ok(())
+}
+
+/// A mapped thread stack, unmapped again on drop.
+pub struct Stack {
+    slot: usize,
+}
+
+impl Stack {
+    /// Maps the stack of a free slot, using the global frame allocator.
+    pub fn new() -> Result<Self, ThreadError> {
+        let slot = take_slot().ok_or(ThreadError::TooManyThreads)?;
+        let bottom = slot_bottom(slot);
+        let mapped = memory::try_with_frame_allocator(|frame_allocator| {
+            // serialized with other users of the kernel page table by the
+            // frame allocator lock, like the heap growing itself
+            let mut mapper = unsafe { memory::kernel_mapper() };
+            memory::map_allocated_prefix(
+                &mut mapper,
+                bottom,
+                STACK_SIZE,
+                STACK_FLAGS,
+                frame_allocator,
+            )
+        });
+        let err = match mapped {
+            Some((_, Ok(()))) => return Ok(Stack { slot }),
+            Some((mapped, Err(err))) => {
+                unmap(bottom, mapped);
+                ThreadError::Map(err)
+            }
+            None => ThreadError::NotInitialized,
+        };
+        free_slot(slot);
+        Err(err)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        slot_bottom(self.slot) + STACK_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unmap(slot_bottom(self.slot), STACK_SIZE);
+        free_slot(self.slot);
+    }
+}
+
+fn slot_bottom(slot: usize) -> VirtAddr {
+    let start = VirtAddr::new(STACKS_START.load(Ordering::SeqCst));
+    start + slot as u64 * STACK_STRIDE + 4096u64
+}
+
+/// Unmaps `size` bytes from `bottom` on and frees their frames.
+fn unmap(bottom: VirtAddr, size: u64) {
+    memory::with_frame_allocator(|frame_allocator| {
+        let mut mapper = unsafe { memory::kernel_mapper() };
+        let start = Page::<Size4KiB>::containing_address(bottom);
+        for page in Page::range(start, start + size / 4096) {
+            let (frame, flush) = mapper.unmap(page).expect("thread stack not mapped");
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    });
+}
+
+// Stacks are freed by the scheduler with interrupts disabled, so the slot
+// bitmap must never be locked with interrupts enabled.
+
+fn take_slot() -> Option<usize> {
+    interrupts::without_interrupts(|| {
+        let mut used = USED_SLOTS.lock();
+        let (index, word) = used
+            .iter_mut()
+            .enumerate()
+            .find(|(_, word)| **word != u64::MAX)?;
+        let bit = word.trailing_ones() as usize;
+        *word |= 1 << bit;
+        Some(index * 64 + bit)
+    })
+}
+
+fn free_slot(slot: usize) {
+    interrupts::without_interrupts(|| {
+        USED_SLOTS.lock()[slot / 64] &= !(1 << (slot % 64));
+    });
+}
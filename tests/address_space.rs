@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::{
+    allocator::init_heap,
+    memory::{
+        self,
+        address_space::{self, AddressSpace},
+        GlobalFrameAllocator, USER_SPACE_START,
+    },
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    address_space::init_kernel_tables(&mut frame_allocator).expect("kernel tables init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(unsafe { PHYS_MEM_OFFSET })
+}
+
+fn free_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.free_frames())
+}
+
+/// Allocates a frame and writes `value` into its first word.
+fn tagged_frame(value: u64) -> PhysFrame {
+    let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let ptr: *mut u64 = (phys_mem_offset() + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { ptr.write_volatile(value) };
+    frame
+}
+
+const USER_PAGE: u64 = USER_SPACE_START as u64 + 0x20_0000;
+
+#[test_case]
+fn test_separate_user_mappings() {
+    let page = Page::containing_address(VirtAddr::new(USER_PAGE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let frames = [tagged_frame(1), tagged_frame(2)];
+
+    let mut spaces = [
+        AddressSpace::new(phys_mem_offset()).unwrap(),
+        AddressSpace::new(phys_mem_offset()).unwrap(),
+    ];
+    for (space, &frame) in spaces.iter_mut().zip(frames.iter()) {
+        unsafe { space.map(page, frame, flags).unwrap() };
+        assert_eq!(
+            Some(frame.start_address()),
+            space.translate_addr(page.start_address())
+        );
+    }
+
+    let kernel_table = Cr3::read().0;
+    // lives on the heap, which all address spaces share
+    let shared = Box::new(42);
+    for (i, space) in spaces.iter().enumerate() {
+        unsafe { space.activate() };
+        assert!(space.is_active());
+        let ptr: *const u64 = page.start_address().as_ptr();
+        assert_eq!(i as u64 + 1, unsafe { ptr.read_volatile() });
+        assert_eq!(42, *shared);
+    }
+
+    // dropping the active address space switches back to the kernel's
+    for (mut space, frame) in IntoIterator::into_iter(spaces).zip(frames.iter()) {
+        assert_eq!(*frame, space.unmap(page).unwrap());
+        assert!(space.translate_addr(page.start_address()).is_none());
+    }
+    assert_eq!(kernel_table, Cr3::read().0);
+
+    for &frame in frames.iter() {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+#[test_case]
+fn test_kernel_mappings_shared() {
+    let mut space = AddressSpace::new(phys_mem_offset()).unwrap();
+    let vga = phys_mem_offset() + 0xb8000u64;
+    assert_eq!(Some(PhysAddr::new(0xb8000)), space.translate_addr(vga));
+    let code = VirtAddr::from_ptr(kernel_main as *const ());
+    assert!(space.translate_addr(code).is_some());
+    assert!(space
+        .translate_addr(VirtAddr::new(USER_SPACE_START as u64))
+        .is_none());
+}
+
+#[test_case]
+fn test_later_kernel_mappings_shared() {
+    let mut space = AddressSpace::new(phys_mem_offset()).unwrap();
+
+    // in a level 4 entry of its own, which had no mappings before
+    let page = Page::containing_address(VirtAddr::new(0x_7000_0000_0000));
+    let frame = tagged_frame(7);
+    memory::with_frame_allocator(|frame_allocator| unsafe {
+        memory::kernel_mapper()
+            .map_to(page, frame, PageTableFlags::PRESENT, frame_allocator)
+            .unwrap()
+            .flush()
+    });
+    assert_eq!(
+        Some(frame.start_address()),
+        space.translate_addr(page.start_address())
+    );
+    unsafe { space.activate() };
+    let ptr: *const u64 = page.start_address().as_ptr();
+    assert_eq!(7, unsafe { ptr.read_volatile() });
+    drop(space);
+
+    memory::with_frame_allocator(|frame_allocator| unsafe {
+        let (frame, flush) = memory::kernel_mapper().unmap(page).unwrap();
+        flush.flush();
+        frame_allocator.deallocate_frame(frame);
+    });
+}
+
+#[test_case]
+fn test_drop_frees_page_tables() {
+    let free = free_frames();
+    {
+        let mut space = AddressSpace::new(phys_mem_offset()).unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+        // spread over several level 3, level 2 and level 1 tables
+        for &offset in &[0, 0x1000, 0x20_0000, 0x4000_0000, 0x80_0000_0000] {
+            let page = Page::containing_address(VirtAddr::new(USER_SPACE_START as u64 + offset));
+            unsafe { space.map(page, frame, flags).unwrap() };
+        }
+        assert!(free_frames() < free);
+    }
+    assert_eq!(free, free_frames());
+}
@@ -0,0 +1,172 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use common::Rng;
+use core::panic::PanicInfo;
+use toy_os::{
+    allocator::init_heap,
+    memory::{
+        self,
+        buddy::{self, with_dma_frame_allocator, BuddyFrameAllocator, DMA_LIMIT, MAX_ORDER},
+    },
+    sprintln,
+};
+use x86_64::{structures::paging::frame::PhysFrameRange, PhysAddr, VirtAddr};
+
+entry_point!(kernel_main);
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    let added = buddy::init_dma_frame_allocator(&mut frame_allocator);
+    sprintln!("DMA frame allocator manages {} frames", added);
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn free_block_counts(buddy: &BuddyFrameAllocator) -> [usize; MAX_ORDER + 1] {
+    let mut counts = [0; MAX_ORDER + 1];
+    for (order, count) in counts.iter_mut().enumerate() {
+        *count = buddy.free_blocks(order);
+    }
+    counts
+}
+
+fn frame_ptr(range: &PhysFrameRange, index: u64) -> *mut u64 {
+    let offset = VirtAddr::new(unsafe { PHYS_MEM_OFFSET });
+    let addr = range.start.start_address().as_u64() + index * 4096;
+    (offset + addr).as_mut_ptr()
+}
+
+#[test_case]
+fn test_allocate_free_round_trip() {
+    with_dma_frame_allocator(|buddy| {
+        let free = buddy.free_frames();
+
+        let block = buddy.allocate(3).unwrap();
+        assert_eq!(8, block.end - block.start);
+        assert!(block.start.start_address().is_aligned(8 * 4096u64));
+        assert_eq!(free - 8, buddy.free_frames());
+
+        unsafe { buddy.deallocate(block) };
+        assert_eq!(free, buddy.free_frames());
+    })
+}
+
+#[test_case]
+fn test_block_is_contiguous() {
+    with_dma_frame_allocator(|buddy| {
+        let block = buddy.allocate(4).unwrap();
+        for i in 0..16 {
+            unsafe { frame_ptr(&block, i).write_volatile(i) };
+        }
+        for i in 0..16 {
+            assert_eq!(i, unsafe { frame_ptr(&block, i).read_volatile() });
+        }
+        unsafe { buddy.deallocate(block) };
+    })
+}
+
+#[test_case]
+fn test_alignment_constraint() {
+    with_dma_frame_allocator(|buddy| {
+        let align = 64 * 1024;
+        let block = buddy
+            .allocate_below(0, align, PhysAddr::new(u64::MAX))
+            .unwrap();
+        assert!(block.start.start_address().is_aligned(align));
+        assert_eq!(1, block.end - block.start);
+        unsafe { buddy.deallocate(block) };
+    })
+}
+
+#[test_case]
+fn test_limit_constraint() {
+    with_dma_frame_allocator(|buddy| {
+        let limit = PhysAddr::new(DMA_LIMIT);
+        let blocks: Vec<_> = (0..16)
+            .map(|_| buddy.allocate_below(2, 4096, limit).unwrap())
+            .collect();
+        for block in blocks.iter() {
+            assert!(block.end.start_address() <= limit);
+        }
+        for block in blocks {
+            unsafe { buddy.deallocate(block) };
+        }
+
+        // nothing fits below the first frames, which are never usable
+        assert!(buddy
+            .allocate_below(0, 4096, PhysAddr::new(0x1000))
+            .is_none());
+    })
+}
+
+#[test_case]
+fn test_too_large_order() {
+    with_dma_frame_allocator(|buddy| {
+        assert!(buddy.allocate(MAX_ORDER + 1).is_none());
+    })
+}
+
+#[test_case]
+fn test_stress_and_coalescing() {
+    with_dma_frame_allocator(|buddy| {
+        let free = buddy.free_frames();
+        let counts = free_block_counts(buddy);
+
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+        let mut live: Vec<(PhysFrameRange, u64)> = Vec::with_capacity(64);
+        for round in 0..4000u64 {
+            let allocate = live.is_empty() || (live.len() < 64 && rng.next() % 3 != 0);
+            if allocate {
+                let order = (rng.next() % 6) as usize;
+                if let Some(block) = buddy.allocate(order) {
+                    // tag every frame; an overlapping block would overwrite tags
+                    for i in 0..(block.end - block.start) {
+                        unsafe { frame_ptr(&block, i).write_volatile(round) };
+                    }
+                    live.push((block, round));
+                }
+            } else {
+                let index = (rng.next() % live.len() as u64) as usize;
+                let (block, tag) = live.swap_remove(index);
+                for i in 0..(block.end - block.start) {
+                    assert_eq!(tag, unsafe { frame_ptr(&block, i).read_volatile() });
+                }
+                unsafe { buddy.deallocate(block) };
+            }
+        }
+
+        for (block, _) in live {
+            unsafe { buddy.deallocate(block) };
+        }
+        assert_eq!(free, buddy.free_frames());
+        // all buddies merged back into the original blocks
+        assert_eq!(counts, free_block_counts(buddy));
+    })
+}
@@ -0,0 +1,216 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::{
+    allocator::init_heap,
+    memory::{
+        self,
+        address_space::{self, AddressSpace},
+        cow, GlobalFrameAllocator, USER_SPACE_START,
+    },
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    address_space::init_kernel_tables(&mut frame_allocator).expect("kernel tables init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+
+    // the reference count map keeps its first node once allocated
+    toy_os::disable_leak_check();
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+const REGION_START: u64 = USER_SPACE_START as u64 + 0x40_0000;
+const REGION_PAGES: u64 = 4;
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(unsafe { PHYS_MEM_OFFSET })
+}
+
+fn free_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.free_frames())
+}
+
+fn region_page(i: u64) -> Page {
+    Page::containing_address(VirtAddr::new(REGION_START + i * 4096))
+}
+
+fn word(i: u64) -> *mut u64 {
+    region_page(i).start_address().as_mut_ptr()
+}
+
+/// Maps a writable region whose pages hold their own index.
+fn new_parent() -> AddressSpace {
+    let mut parent = AddressSpace::new(phys_mem_offset()).unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for i in 0..REGION_PAGES {
+        let frame = GlobalFrameAllocator.allocate_frame().unwrap();
+        let ptr: *mut u64 = (phys_mem_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(i);
+            parent.map(region_page(i), frame, flags).unwrap();
+        }
+    }
+    parent
+}
+
+fn frames(space: &mut AddressSpace) -> Vec<PhysFrame> {
+    (0..REGION_PAGES)
+        .map(|i| {
+            let addr = space
+                .translate_addr(region_page(i).start_address())
+                .unwrap();
+            PhysFrame::containing_address(addr)
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_fork_shares_frames() {
+    let mut parent = new_parent();
+    let mut child = parent.fork().unwrap();
+
+    let parent_frames = frames(&mut parent);
+    assert_eq!(parent_frames, frames(&mut child));
+    for &frame in parent_frames.iter() {
+        assert_eq!(2, cow::ref_count(frame));
+    }
+
+    unsafe { child.activate() };
+    for i in 0..REGION_PAGES {
+        assert_eq!(i, unsafe { word(i).read_volatile() });
+    }
+}
+
+#[test_case]
+fn test_write_copies_frame() {
+    let mut parent = new_parent();
+    let mut child = parent.fork().unwrap();
+    let shared = frames(&mut parent);
+
+    unsafe {
+        parent.activate();
+        word(0).write_volatile(100);
+    }
+    let parent_frames = frames(&mut parent);
+    assert_ne!(shared[0], parent_frames[0]);
+    assert_eq!(&shared[1..], &parent_frames[1..]);
+    assert_eq!(1, cow::ref_count(shared[0]));
+
+    unsafe {
+        child.activate();
+        // the child still sees the old value
+        assert_eq!(0, word(0).read_volatile());
+        // and as the last sharer, it gets the frame back without a copy
+        word(0).write_volatile(200);
+    }
+    assert_eq!(shared[0], frames(&mut child)[0]);
+
+    unsafe { parent.activate() };
+    assert_eq!(100, unsafe { word(0).read_volatile() });
+    assert_eq!(1, unsafe { word(1).read_volatile() });
+}
+
+#[test_case]
+fn test_fork_of_fork() {
+    let mut parent = new_parent();
+    let mut child = parent.fork().unwrap();
+    let grandchild = child.fork().unwrap();
+    let shared = frames(&mut parent);
+    assert_eq!(3, cow::ref_count(shared[2]));
+
+    unsafe {
+        grandchild.activate();
+        word(2).write_volatile(7);
+    }
+    assert_eq!(2, cow::ref_count(shared[2]));
+    drop(grandchild);
+    drop(child);
+    assert_eq!(1, cow::ref_count(shared[2]));
+
+    unsafe { parent.activate() };
+    assert_eq!(2, unsafe { word(2).read_volatile() });
+}
+
+#[test_case]
+fn test_drop_frees_shared_frames() {
+    let free = free_frames();
+    {
+        let mut parent = new_parent();
+        let child = parent.fork().unwrap();
+        unsafe {
+            child.activate();
+            word(0).write_volatile(1);
+            word(3).write_volatile(1);
+        }
+        drop(parent);
+        drop(child);
+    }
+    assert_eq!(free, free_frames());
+}
+
+#[test_case]
+fn test_failed_fork_leaves_frames_unshared() {
+    let mut parent = new_parent();
+    let shared = frames(&mut parent);
+    let mut hoard = Vec::with_capacity(free_frames());
+    let free = free_frames();
+
+    // leave frames for the child's level 4 and level 3 tables only, so
+    // that mapping the first page fails
+    memory::with_frame_allocator(|frame_allocator| {
+        while frame_allocator.free_frames() > 2 {
+            hoard.push(frame_allocator.allocate_frame().unwrap());
+        }
+    });
+    let result = parent.fork();
+    memory::with_frame_allocator(|frame_allocator| {
+        for frame in hoard.drain(..) {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    });
+    assert!(result.is_err());
+    assert_eq!(free, free_frames());
+    for &frame in shared.iter() {
+        assert_eq!(1, cow::ref_count(frame));
+    }
+
+    // as the only user, the parent writes without copying
+    unsafe {
+        parent.activate();
+        word(0).write_volatile(100);
+    }
+    assert_eq!(shared, frames(&mut parent));
+    assert_eq!(100, unsafe { word(0).read_volatile() });
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use lazy_static::lazy_static;
+use toy_os::{allocator::init_heap, exit_qemu, memory, sprint, sprintln, QemuExitCode};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    sprint!("execute_heap::execute_heap...\t");
+
+    toy_os::gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    unsafe { memory::sections::protect_kernel(&mut mapper, &boot_info.memory_map) }
+        .expect("kernel remapping failed");
+
+    // a single `ret` instruction on the heap
+    let code = Box::leak(Box::new([0xc3u8]));
+    TARGET.store(code.as_ptr() as u64, Ordering::SeqCst);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    sprintln!("[failed]");
+    sprintln!("Execution continued after executing the heap");
+    exit_qemu(QemuExitCode::Failure);
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH;
+    if error_code.contains(expected) && Cr2::read().as_u64() == TARGET.load(Ordering::SeqCst) {
+        sprintln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        sprintln!("[failed]");
+        sprintln!(
+            "unexpected page fault at {:?}: {:?}",
+            Cr2::read(),
+            error_code
+        );
+        exit_qemu(QemuExitCode::Failure);
+    }
+    toy_os::hlt_loop();
+}
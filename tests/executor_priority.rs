@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{cell::RefCell, future::Future, panic::PanicInfo};
+use toy_os::{
+    allocator::init_heap,
+    memory,
+    task::{
+        executor::{Executor, AGING_LIMIT},
+        yield_now, Priority, Task,
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// Nothing here waits for interrupts: every task is ready until it is done,
+// so `run_ready_tasks` polls them in a fixed order.
+
+type Log = Rc<RefCell<Vec<&'static str>>>;
+
+/// Logs `name` `times` times, yielding after each.
+fn chatty(log: &Log, name: &'static str, times: usize) -> impl Future<Output = ()> {
+    let log = log.clone();
+    async move {
+        for _ in 0..times {
+            log.borrow_mut().push(name);
+            yield_now().await;
+        }
+    }
+}
+
+#[test_case]
+fn test_higher_classes_first() {
+    let log = Log::default();
+    let mut executor = Executor::new();
+    let spawn = |executor: &mut Executor, name, priority| {
+        executor.spawn(Task::with_priority(chatty(&log, name, 1), priority));
+    };
+    spawn(&mut executor, "low", Priority::Low);
+    spawn(&mut executor, "normal 1", Priority::Normal);
+    spawn(&mut executor, "high", Priority::High);
+    spawn(&mut executor, "normal 2", Priority::Normal);
+    executor.run_ready_tasks();
+
+    assert_eq!(vec!["high", "normal 1", "normal 2", "low"], *log.borrow());
+}
+
+#[test_case]
+fn test_aging() {
+    let log = Log::default();
+    let mut executor = Executor::new();
+    executor.spawn(Task::with_priority(
+        chatty(&log, "high", 3 * AGING_LIMIT),
+        Priority::High,
+    ));
+    executor.spawn(Task::with_priority(
+        chatty(&log, "normal", 1),
+        Priority::Normal,
+    ));
+    executor.spawn(Task::with_priority(chatty(&log, "low", 1), Priority::Low));
+    executor.run_ready_tasks();
+
+    // both lower classes age at the same pace, the lowest goes first
+    let log = log.borrow();
+    assert!(log[..AGING_LIMIT].iter().all(|&name| name == "high"));
+    assert_eq!(["low", "normal"], log[AGING_LIMIT..AGING_LIMIT + 2]);
+    assert_eq!(3 * AGING_LIMIT + 2, log.len());
+}
+
+#[test_case]
+fn test_change_priority() {
+    let log = Log::default();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(chatty(&log, "a", 3)));
+    let b = executor.spawn(Task::new(chatty(&log, "b", 3)));
+    assert_eq!(Priority::Normal, b.get());
+
+    // b is queued behind a already, and only moves up once it wakes again
+    b.set(Priority::High);
+    assert_eq!(Priority::High, b.get());
+    executor.run_ready_tasks();
+
+    assert_eq!(vec!["a", "b", "b", "b", "a", "a"], *log.borrow());
+}
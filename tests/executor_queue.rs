@@ -0,0 +1,179 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use toy_os::{
+    allocator::{self, init_heap},
+    memory,
+    task::{executor::Executor, yield_now, Task},
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+/// Never finishes; counts its polls and hands out its waker.
+struct Parked {
+    polls: Rc<Cell<usize>>,
+    waker: Rc<RefCell<Option<Waker>>>,
+}
+
+impl Future for Parked {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.polls.set(self.polls.get() + 1);
+        *self.waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+fn parked() -> (Task, Rc<Cell<usize>>, Rc<RefCell<Option<Waker>>>) {
+    let polls = Rc::new(Cell::new(0));
+    let waker = Rc::new(RefCell::new(None));
+    let task = Task::new(Parked {
+        polls: polls.clone(),
+        waker: waker.clone(),
+    });
+    (task, polls, waker)
+}
+
+fn allocations() -> usize {
+    let stats = allocator::stats();
+    stats
+        .classes
+        .iter()
+        .map(|class| class.allocations)
+        .sum::<usize>()
+        + stats.fallback_allocations
+}
+
+#[test_case]
+fn test_many_tasks() {
+    let mut executor = Executor::new();
+    let done = Rc::new(Cell::new(0));
+    for _ in 0..1000 {
+        let done = done.clone();
+        executor.spawn(Task::new(async move {
+            yield_now().await;
+            yield_now().await;
+            done.set(done.get() + 1);
+        }));
+    }
+    assert_eq!(1000, executor.len());
+    executor.run_ready_tasks();
+    assert_eq!(1000, done.get());
+    assert!(executor.is_empty());
+}
+
+#[test_case]
+fn test_with_capacity() {
+    let mut executor = Executor::with_capacity(64);
+    let capacity = executor.capacity();
+    assert!(capacity >= 64);
+    for _ in 0..64 {
+        executor.spawn(Task::new(async {}));
+    }
+    assert_eq!(capacity, executor.capacity());
+    executor.run_ready_tasks();
+    assert!(executor.is_empty());
+}
+
+#[test_case]
+fn test_wakes_coalesced() {
+    let mut executor = Executor::new();
+    let (task, polls, waker) = parked();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+    assert_eq!(1, polls.get());
+
+    let waker = waker.borrow_mut().take().unwrap();
+    for _ in 0..1000 {
+        waker.wake_by_ref();
+    }
+    executor.run_ready_tasks();
+    assert_eq!(2, polls.get());
+}
+
+#[test_case]
+fn test_wake_does_not_allocate() {
+    let mut executor = Executor::new();
+    let (task, _polls, waker) = parked();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+
+    let waker = waker.borrow_mut().take().unwrap();
+    let before = allocations();
+    waker.wake_by_ref();
+    waker.wake_by_ref();
+    assert_eq!(before, allocations());
+    executor.run_ready_tasks();
+}
+
+#[test_case]
+fn test_task_id_reuse() {
+    let mut executor = Executor::new();
+    let stale = Rc::new(RefCell::new(None));
+    let stale_clone = stale.clone();
+    executor.spawn(Task::new(async move {
+        futures_util::future::poll_fn(|cx| {
+            *stale_clone.borrow_mut() = Some(cx.waker().clone());
+            Poll::Ready(())
+        })
+        .await
+    }));
+    executor.run_ready_tasks();
+    assert!(executor.is_empty());
+
+    // takes over the id of a finished task
+    let (task, polls, _) = parked();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+    assert_eq!(1, polls.get());
+
+    stale.borrow_mut().take().unwrap().wake();
+    executor.run_ready_tasks();
+    assert_eq!(1, polls.get());
+}
+
+#[test_case]
+fn test_waker_outlives_executor() {
+    let mut executor = Executor::new();
+    let (task, _, waker) = parked();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+    let waker = waker.borrow_mut().take().unwrap();
+    drop(executor);
+    waker.wake_by_ref();
+    waker.wake();
+}
@@ -0,0 +1,223 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::alloc::{alloc, dealloc};
+use bootloader::{entry_point, BootInfo};
+use common::poll_once;
+use core::{alloc::Layout, panic::PanicInfo, ptr, task::Poll};
+use toy_os::{
+    allocator::{
+        heap_info, init_heap, reserve, reserved, try_box, try_vec_with_capacity, AllocError,
+        HEAP_MAX_SIZE,
+    },
+    memory,
+    task::{
+        executor::{Executor, SpawnError},
+        join::JoinError,
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_try_box() {
+    let boxed = try_box([7u64; 64]).expect("small box failed");
+    assert!(boxed.iter().all(|&x| x == 7));
+    assert_eq!(Ok(()), try_box(()).map(|unit| *unit));
+}
+
+#[test_case]
+fn test_try_vec_with_capacity() {
+    let mut vec = try_vec_with_capacity::<u32>(1000).expect("small vec failed");
+    assert!(vec.capacity() >= 1000);
+    vec.extend(0..1000);
+    assert_eq!(999, vec[999]);
+}
+
+#[test_case]
+fn test_out_of_memory() {
+    match try_vec_with_capacity::<u8>(2 * HEAP_MAX_SIZE) {
+        Err(AllocError::OutOfMemory(layout)) => assert_eq!(2 * HEAP_MAX_SIZE, layout.size()),
+        other => panic!("unexpected result {:?}", other.map(|vec| vec.capacity())),
+    }
+    assert_eq!(
+        Err(AllocError::CapacityOverflow),
+        try_vec_with_capacity::<u64>(usize::MAX).map(|vec| vec.capacity())
+    );
+}
+
+#[test_case]
+fn test_reservation() {
+    let size = 256 * 1024;
+    let used = heap_info().used;
+    let reservation = reserve(size).expect("reservation failed");
+    assert_eq!(size, reservation.size());
+    assert_eq!(size, reserved());
+    assert!(heap_info().used >= used + size);
+
+    reservation.release();
+    assert_eq!(0, reserved());
+    assert!(matches!(
+        reserve(2 * HEAP_MAX_SIZE),
+        Err(AllocError::OutOfMemory(_))
+    ));
+    assert_eq!(0, reserved());
+}
+
+#[test_case]
+fn test_try_spawn() {
+    let mut executor = Executor::new();
+    // more than the old fixed queue held
+    for _ in 0..200 {
+        assert!(executor.try_spawn(async {}).is_ok());
+    }
+    executor.run_ready_tasks();
+    assert!(executor.is_empty());
+}
+
+/// Number of block sizes `exhaust_heap` tries, the largest being 128 MiB.
+const EXHAUST_SIZES: usize = 25;
+
+/// Holds all heap memory there is, until dropped.
+struct Exhausted {
+    /// Per block size, a list of blocks linked through their first word.
+    lists: [*mut u8; EXHAUST_SIZES],
+}
+
+fn exhaust_layout(index: usize) -> Layout {
+    Layout::from_size_align(8 << index, 8).unwrap()
+}
+
+/// Allocates blocks of halving sizes until not even 8 bytes are left,
+/// growing the heap as far as it goes.
+fn exhaust_heap() -> Exhausted {
+    let mut exhausted = Exhausted {
+        lists: [ptr::null_mut(); EXHAUST_SIZES],
+    };
+    for index in (0..EXHAUST_SIZES).rev() {
+        loop {
+            let block = unsafe { alloc(exhaust_layout(index)) };
+            if block.is_null() {
+                break;
+            }
+            unsafe { (block as *mut *mut u8).write(exhausted.lists[index]) };
+            exhausted.lists[index] = block;
+        }
+    }
+    exhausted
+}
+
+impl Drop for Exhausted {
+    fn drop(&mut self) {
+        for (index, &list) in self.lists.iter().enumerate() {
+            let mut block = list;
+            while !block.is_null() {
+                let next = unsafe { (block as *mut *mut u8).read() };
+                unsafe { dealloc(block, exhaust_layout(index)) };
+                block = next;
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_try_spawn_out_of_memory() {
+    if cfg!(feature = "bump-allocator") {
+        // gets nothing back while anything else is allocated
+        return;
+    }
+    let mut executor = Executor::new();
+    let exhausted = exhaust_heap();
+    match executor.try_spawn(async {}) {
+        Err(SpawnError::OutOfMemory(_)) => {}
+        other => panic!("expected OutOfMemory, got {:?}", other.map(|_| ())),
+    }
+    assert!(executor.is_empty());
+
+    drop(exhausted);
+    assert!(executor.try_spawn(async {}).is_ok());
+    executor.run_ready_tasks();
+}
+
+#[test_case]
+fn test_spawner_out_of_memory() {
+    if cfg!(feature = "bump-allocator") {
+        // gets nothing back while anything else is allocated
+        return;
+    }
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let mut handle = spawner.spawn_with_handle(async { 6 * 7 });
+    let exhausted = exhaust_heap();
+    match spawner.try_spawn(async {}) {
+        Err(SpawnError::OutOfMemory(_)) => {}
+        other => panic!("expected OutOfMemory, got {:?}", other.map(|_| ())),
+    }
+
+    // the queued task does not fit into the executor
+    executor.run_ready_tasks();
+    assert_eq!(1, executor.failed_spawns());
+    assert!(executor.is_empty());
+    assert_eq!(
+        Poll::Ready(Err(JoinError::Cancelled)),
+        poll_once(&mut handle)
+    );
+
+    drop(exhausted);
+    assert!(spawner.try_spawn(async {}).is_ok());
+    executor.run_ready_tasks();
+    assert_eq!(1, executor.failed_spawns());
+    assert!(executor.is_empty());
+}
+
+#[test_case]
+fn test_reservation_when_exhausted() {
+    let size = 256 * 1024;
+    let mut reservation = reserve(size).expect("reservation failed");
+    let exhausted = exhaust_heap();
+    assert!(try_vec_with_capacity::<u8>(size / 2).is_err());
+
+    let layout = Layout::from_size_align(size / 2, 8).unwrap();
+    let ptr = reservation
+        .allocate(layout)
+        .expect("pool allocation failed");
+    assert!(reservation.used() >= size / 2);
+    unsafe {
+        ptr.as_ptr().write_bytes(0xab, layout.size());
+        reservation.deallocate(ptr, layout);
+    }
+    assert_eq!(0, reservation.used());
+    assert!(reservation
+        .allocate(Layout::from_size_align(2 * size, 8).unwrap())
+        .is_err());
+
+    drop(exhausted);
+    reservation.release();
+}
@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{
+    alloc::{alloc, dealloc},
+    vec::Vec,
+};
+use bootloader::{entry_point, BootInfo};
+use core::{alloc::Layout, panic::PanicInfo};
+use toy_os::{
+    allocator::{
+        fixed_size_block::{list_index, FixedSizedBlockAllocator, BLOCK_SIZES, MAX_CACHED_BYTES},
+        init_heap, Backend,
+    },
+    memory,
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+/// Runs `f` with an allocator of its own, on `size` bytes of the heap.
+fn with_allocator(size: usize, f: impl FnOnce(&mut FixedSizedBlockAllocator)) {
+    let layout = Layout::from_size_align(size, 4096).unwrap();
+    let heap = unsafe { alloc(layout) };
+    assert!(!heap.is_null());
+    let mut allocator = FixedSizedBlockAllocator::new();
+    unsafe { allocator.init(heap as usize, size) };
+    f(&mut allocator);
+    unsafe { dealloc(heap, layout) };
+}
+
+fn class(size: usize) -> usize {
+    list_index(&Layout::from_size_align(size, 8).unwrap()).unwrap()
+}
+
+#[test_case]
+fn test_large_size_classes() {
+    assert_eq!(4096, *BLOCK_SIZES.last().unwrap());
+    with_allocator(64 * 1024, |allocator| {
+        let layout = Layout::from_size_align(2000, 8).unwrap();
+        let ptr = allocator.allocate(layout);
+        assert!(!ptr.is_null());
+        assert_eq!(0, ptr as usize % 2048);
+        unsafe { allocator.deallocate(ptr, layout) };
+        assert_eq!(1, allocator.cached(class(2000)));
+
+        // the cached block is used again
+        assert_eq!(ptr, allocator.allocate(layout));
+        unsafe { allocator.deallocate(ptr, layout) };
+    });
+}
+
+#[test_case]
+fn test_reclaim_when_heap_is_full() {
+    let size = 64 * 1024;
+    with_allocator(size, |allocator| {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = allocator.allocate(layout);
+            if ptr.is_null() {
+                break;
+            }
+            blocks.push(ptr);
+        }
+        for &ptr in blocks.iter() {
+            unsafe { allocator.deallocate(ptr, layout) };
+        }
+        assert_eq!(blocks.len(), allocator.cached(class(64)));
+
+        // only fits once the cached blocks are back in the heap
+        let large = Layout::from_size_align(size / 2, 8).unwrap();
+        let ptr = allocator.allocate(large);
+        assert!(!ptr.is_null());
+        assert_eq!(0, allocator.cached(class(64)));
+        unsafe { allocator.deallocate(ptr, large) };
+    });
+}
+
+#[test_case]
+fn test_cache_limit() {
+    with_allocator(1024 * 1024, |allocator| {
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let limit = MAX_CACHED_BYTES / 4096;
+        let blocks: Vec<_> = (0..limit + 4).map(|_| allocator.allocate(layout)).collect();
+        assert!(blocks.iter().all(|ptr| !ptr.is_null()));
+        let used = allocator.used();
+
+        for &ptr in blocks.iter() {
+            unsafe { allocator.deallocate(ptr, layout) };
+        }
+        assert_eq!(limit, allocator.cached(class(4096)));
+        // the surplus went back to the fallback heap
+        assert_eq!(used - 4 * 4096, allocator.used());
+
+        assert_eq!(limit, allocator.reclaim());
+        assert_eq!(0, allocator.used());
+    });
+}
+
+#[test_case]
+fn test_realloc_in_place() {
+    with_allocator(64 * 1024, |allocator| {
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let ptr = allocator.allocate(layout);
+        assert!(allocator.realloc_in_place(ptr, layout, 64));
+        assert!(allocator.realloc_in_place(ptr, layout, 33));
+        assert!(!allocator.realloc_in_place(ptr, layout, 65));
+        assert!(!allocator.realloc_in_place(ptr, layout, 32));
+
+        let large = Layout::from_size_align(8192, 8).unwrap();
+        let large_ptr = allocator.allocate(large);
+        assert!(!allocator.realloc_in_place(large_ptr, large, 8000));
+        unsafe {
+            allocator.deallocate(large_ptr, large);
+            allocator.deallocate(ptr, layout);
+        }
+    });
+}
+
+#[test_case]
+fn test_global_realloc_keeps_small_blocks() {
+    let mut vec: Vec<u8> = Vec::with_capacity(40);
+    vec.extend_from_slice(&[1; 40]);
+    let ptr = vec.as_ptr();
+    vec.reserve_exact(20);
+    if toy_os::allocator::BACKEND == "fixed-size-block" && !cfg!(feature = "heap-debug") {
+        assert_eq!(ptr, vec.as_ptr());
+    }
+    assert!(vec.iter().all(|&byte| byte == 1));
+}
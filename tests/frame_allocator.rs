@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::{allocator::init_heap, memory};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+static FRAME_ALLOCATOR: Mutex<Option<memory::BootInfoFrameAllocator>> = Mutex::new(None);
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_counters() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    assert!(allocator.free_frames() > 0);
+    assert_eq!(
+        allocator.usable_frames(),
+        allocator.free_frames() + allocator.used_frames()
+    );
+
+    let free = allocator.free_frames();
+    let frame = allocator.allocate_frame().unwrap();
+    assert!(allocator.is_allocated(frame));
+    assert_eq!(free - 1, allocator.free_frames());
+    unsafe { allocator.deallocate_frame(frame) };
+    assert!(!allocator.is_allocated(frame));
+    assert_eq!(free, allocator.free_frames());
+}
+
+#[test_case]
+fn test_frames_are_distinct_and_usable() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let phys_mem_offset = VirtAddr::new(unsafe { PHYS_MEM_OFFSET });
+
+    let frames: Vec<_> = (0..64)
+        .map(|_| allocator.allocate_frame().unwrap())
+        .collect();
+    for (i, frame) in frames.iter().enumerate() {
+        assert!(frame.start_address().as_u64() >= memory::LOW_MEMORY_END);
+        assert!(frames[..i].iter().all(|other| other != frame));
+        // tag every frame, then check that no two of them alias
+        let ptr: *mut u64 = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { ptr.write_volatile(i as u64) };
+    }
+    for (i, frame) in frames.iter().enumerate() {
+        let ptr: *const u64 = (phys_mem_offset + frame.start_address().as_u64()).as_ptr();
+        assert_eq!(i as u64, unsafe { ptr.read_volatile() });
+    }
+
+    for frame in frames {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+}
+
+#[test_case]
+fn test_freed_frame_is_reused() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    for _ in 0..1000 {
+        let frame = allocator.allocate_frame().unwrap();
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(frame, allocator.allocate_frame().unwrap());
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+}
+
+#[test_case]
+fn test_allocate_all_and_free() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let phys_mem_offset = VirtAddr::new(unsafe { PHYS_MEM_OFFSET });
+    let free = allocator.free_frames();
+
+    // too many frames for the heap, so chain them through their first word
+    let mut head = 0u64;
+    let mut count = 0;
+    while let Some(frame) = allocator.allocate_frame() {
+        let addr = frame.start_address().as_u64();
+        let ptr: *mut u64 = (phys_mem_offset + addr).as_mut_ptr();
+        unsafe { ptr.write(head) };
+        head = addr;
+        count += 1;
+    }
+    assert_eq!(free, count);
+    assert_eq!(0, allocator.free_frames());
+
+    while head != 0 {
+        let ptr: *const u64 = (phys_mem_offset + head).as_ptr();
+        let next = unsafe { ptr.read() };
+        let frame = PhysFrame::containing_address(PhysAddr::new(head));
+        unsafe { allocator.deallocate_frame(frame) };
+        head = next;
+    }
+    assert_eq!(free, allocator.free_frames());
+}
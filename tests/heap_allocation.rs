@@ -6,10 +6,14 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, rc::Rc, vec};
+use alloc::alloc::{alloc, dealloc};
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use core::{mem::align_of, mem::size_of, panic::PanicInfo};
-use toy_os::{allocator::init_heap, memory, sprintln};
+use core::{alloc::Layout, mem::align_of, mem::size_of, panic::PanicInfo, ptr};
+use toy_os::{
+    allocator::{self, init_heap},
+    memory, sprintln,
+};
 use x86_64::VirtAddr;
 
 entry_point!(kernel_main);
@@ -19,9 +23,10 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
     init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    memory::init_global_frame_allocator(frame_allocator);
 
     test_main();
     toy_os::hlt_loop();
@@ -62,3 +67,149 @@ fn test_rc() {
     drop(rc);
     assert_eq!(1, Rc::strong_count(&rc_clone));
 }
+
+#[test_case]
+fn test_stats_size_classes() {
+    let before = allocator::stats();
+    let value = Box::new(1u64);
+    let during = allocator::stats();
+    drop(value);
+    let after = allocator::stats();
+
+    // `u64` goes to the 8 byte class
+    let class = |stats: &allocator::AllocatorStats| stats.classes[0];
+    assert_eq!(8, class(&before).block_size);
+    assert_eq!(class(&before).allocations + 1, class(&during).allocations);
+    assert_eq!(class(&before).frees + 1, class(&after).frees);
+    if cfg!(feature = "fixed-size-block-allocator") {
+        // only that backend keeps freed blocks in per-class caches
+        assert!(class(&after).cached >= 1);
+    }
+    assert_eq!(before.net_allocations() + 1, during.net_allocations());
+    assert_eq!(before.net_allocations(), after.net_allocations());
+}
+
+#[test_case]
+fn test_stats_fallback() {
+    let before = allocator::stats();
+    let vec: Vec<u8> = Vec::with_capacity(8192);
+    let during = allocator::stats();
+    drop(vec);
+    let after = allocator::stats();
+
+    assert_eq!(before.fallback_allocations + 1, during.fallback_allocations);
+    assert_eq!(before.fallback_bytes + 8192, during.fallback_bytes);
+    assert!(during.fallback_peak_bytes >= during.fallback_bytes);
+    assert_eq!(before.fallback_frees + 1, after.fallback_frees);
+    assert_eq!(before.fallback_bytes, after.fallback_bytes);
+    assert_eq!(during.fallback_peak_bytes, after.fallback_peak_bytes);
+}
+
+/// Allocates more than the largest buddy block, 2 MiB. The buddy backend
+/// has to refuse without growing the heap; the others grow the heap.
+#[test_case]
+fn test_allocation_larger_than_growth() {
+    let layout = Layout::from_size_align(3 * 1024 * 1024, 8).unwrap();
+    let before = allocator::heap_info();
+    let ptr = unsafe { alloc(layout) };
+    if cfg!(feature = "buddy-allocator") {
+        assert!(ptr.is_null());
+        assert_eq!(before.size, allocator::heap_info().size);
+    } else {
+        assert!(!ptr.is_null());
+        unsafe {
+            ptr.write_bytes(0xab, layout.size());
+            dealloc(ptr, layout);
+        }
+    }
+}
+
+fn cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Allocates and frees blocks of mixed sizes, keeping up to 64 of them
+/// alive, and reports the cycles per operation.
+#[test_case]
+fn test_benchmark_throughput() {
+    const ROUNDS: usize = 4000;
+    const SLOTS: usize = 64;
+    let sizes = [8, 24, 64, 200, 512, 1000, 4096, 8192];
+    let mut slots = [(ptr::null_mut::<u8>(), Layout::new::<u8>()); SLOTS];
+
+    let start = cycles();
+    for round in 0..ROUNDS {
+        let slot = &mut slots[round % SLOTS];
+        if !slot.0.is_null() {
+            unsafe { dealloc(slot.0, slot.1) };
+        }
+        let layout = Layout::from_size_align(sizes[round % sizes.len()], 8).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        *slot = (ptr, layout);
+    }
+    for &(ptr, layout) in slots.iter() {
+        unsafe { dealloc(ptr, layout) };
+    }
+    let operations = 2 * ROUNDS as u64;
+
+    sprintln!(
+        "\n{}: {} cycles per allocation or free",
+        allocator::BACKEND,
+        (cycles() - start) / operations
+    );
+}
+
+/// Returns the size of the largest block `alloc` hands out, up to `max`.
+fn largest_allocation(max: usize) -> usize {
+    let (mut low, mut high) = (0, max);
+    while low < high {
+        let size = (low + high + 1) / 2;
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            high = size - 1;
+        } else {
+            unsafe { dealloc(ptr, layout) };
+            low = size;
+        }
+    }
+    low
+}
+
+/// Frees every other block of an interleaved pattern and reports how much
+/// of the free memory is still usable for one large allocation.
+#[test_case]
+fn test_fragmentation() {
+    const COUNT: usize = 256;
+    let small = Layout::from_size_align(256, 8).unwrap();
+    let large = Layout::from_size_align(2048, 8).unwrap();
+    let mut smalls = Vec::with_capacity(COUNT);
+    let mut larges = Vec::with_capacity(COUNT);
+    for _ in 0..COUNT {
+        larges.push(unsafe { alloc(large) });
+        smalls.push(unsafe { alloc(small) });
+    }
+    for &ptr in larges.iter() {
+        unsafe { dealloc(ptr, large) };
+    }
+
+    // measure the heap as it is, without growing it
+    let info = allocator::heap_info();
+    allocator::set_heap_limit(info.size);
+    let free = info.size - info.used;
+    let largest = largest_allocation(free);
+    allocator::set_heap_limit(usize::MAX);
+
+    for &ptr in smalls.iter() {
+        unsafe { dealloc(ptr, small) };
+    }
+    assert!(largest <= free);
+    sprintln!(
+        "\n{}: {} of {} free bytes usable at once, {}% fragmentation",
+        allocator::BACKEND,
+        largest,
+        free,
+        100 - largest * 100 / free.max(1)
+    );
+}
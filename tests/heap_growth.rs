@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{alloc::Layout, panic::PanicInfo};
+use toy_os::{
+    allocator::{heap_info, init_heap, set_heap_limit, HEAP_MAX_SIZE, HEAP_SIZE},
+    memory,
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+/// Small enough for every allocator backend.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[test_case]
+fn test_heap_grows() {
+    let before = heap_info();
+    assert_eq!(HEAP_MAX_SIZE, before.limit);
+
+    // more than the whole initial heap
+    let count = 4 * HEAP_SIZE / CHUNK_SIZE;
+    let chunks: Vec<Vec<u8>> = (0..count).map(|i| vec![i as u8; CHUNK_SIZE]).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert!(chunk.iter().all(|&byte| byte == i as u8));
+    }
+
+    let grown = heap_info();
+    assert!(grown.size >= 4 * HEAP_SIZE);
+    assert!(grown.used >= before.used + 4 * HEAP_SIZE);
+    assert!(grown.peak_used >= grown.used);
+
+    drop(chunks);
+    let after = heap_info();
+    // the heap keeps its size, the peak stays
+    assert_eq!(grown.size, after.size);
+    assert_eq!(before.used, after.used);
+    assert_eq!(grown.peak_used, after.peak_used);
+}
+
+#[test_case]
+fn test_heap_limit() {
+    let size = heap_info().size;
+    set_heap_limit(size);
+    assert_eq!(size, heap_info().limit);
+
+    let layout = Layout::from_size_align(CHUNK_SIZE, 8).unwrap();
+    let mut chunks = Vec::with_capacity(size / CHUNK_SIZE);
+    loop {
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        if ptr.is_null() {
+            break;
+        }
+        chunks.push(ptr);
+        assert!(chunks.len() <= size / CHUNK_SIZE);
+    }
+    assert_eq!(size, heap_info().size);
+
+    set_heap_limit(usize::MAX);
+    assert_eq!(HEAP_MAX_SIZE, heap_info().limit);
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(!ptr.is_null());
+    assert!(heap_info().size > size);
+    chunks.push(ptr);
+
+    for ptr in chunks {
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+    }
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::alloc::{alloc, dealloc};
+use bootloader::{entry_point, BootInfo};
+use common::Buffer;
+use core::{
+    alloc::Layout,
+    fmt::Write,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use toy_os::{allocator::init_heap, exit_qemu, memory, sprint, sprintln, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+/// Address of the allocation that is freed wrongly, set right before the
+/// operation that has to panic.
+static EXPECT_PANIC_AT: AtomicUsize = AtomicUsize::new(0);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    should_fail();
+    sprintln!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failure);
+    toy_os::hlt_loop();
+}
+
+/// Frees an allocation with a different size than it was allocated with,
+/// which `heap-debug` reports.
+fn should_fail() {
+    sprint!("heap_layout_mismatch::should_fail...\t");
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let wrong = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        EXPECT_PANIC_AT.store(ptr as usize, Ordering::SeqCst);
+        dealloc(ptr, wrong);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let ptr = EXPECT_PANIC_AT.load(Ordering::SeqCst);
+    if ptr == 0 {
+        toy_os::test_panic_handler(info);
+    }
+    let mut message = Buffer::new();
+    let _ = write!(message, "{}", info);
+    let mut expected = Buffer::new();
+    let _ = write!(
+        expected,
+        "at {:p} has size 24 and align 8",
+        ptr as *const u8
+    );
+    if !message.as_str().contains("allocation #") || !message.as_str().contains(expected.as_str()) {
+        sprintln!("[failed]");
+        sprintln!("panic does not name the allocation: {}", info);
+        exit_qemu(QemuExitCode::Failure);
+        toy_os::hlt_loop();
+    }
+    sprintln!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
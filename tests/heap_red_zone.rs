@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    alloc::Layout,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use toy_os::{allocator::init_heap, exit_qemu, memory, sprint, sprintln, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+/// Set right before the operation that has to panic.
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    should_fail();
+    sprintln!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failure);
+    toy_os::hlt_loop();
+}
+
+/// Writes past the end of an allocation, which `heap-debug` reports on free.
+fn should_fail() {
+    sprint!("heap_red_zone::should_fail...\t");
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        // one byte past the end
+        ptr.add(24).write(0);
+        EXPECT_PANIC.store(true, Ordering::SeqCst);
+        dealloc(ptr, layout);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !EXPECT_PANIC.load(Ordering::SeqCst) {
+        toy_os::test_panic_handler(info);
+    }
+    sprintln!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    toy_os::hlt_loop();
+}
@@ -0,0 +1,195 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{
+    alloc::{alloc, dealloc},
+    vec::Vec,
+};
+use bootloader::{entry_point, BootInfo};
+use common::Rng;
+use core::{alloc::Layout, panic::PanicInfo, slice};
+use toy_os::{
+    allocator::{self, fixed_size_block::BLOCK_SIZES, init_heap},
+    memory,
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+/// Seeds of the default run. A failure reports its seed, which can be
+/// replayed alone with `HEAP_STRESS_SEED=<seed> cargo test --test heap_stress`.
+const SEEDS: [u64; 3] = [0x5eed_0001, 0xdead_beef_cafe_f00d, 0x0123_4567_89ab_cdef];
+
+const STEPS: usize = 10_000;
+/// Allocations alive at the same time, at most.
+const MAX_LIVE: usize = 256;
+/// Largest fallback allocation.
+const MAX_SIZE: usize = 16 * 1024;
+
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+    /// The step that allocated it, which the fill depends on.
+    id: u64,
+}
+
+impl Block {
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    /// The fill of byte `offset`. It differs between neighbouring bytes and
+    /// between blocks, so that a block sharing memory with another one
+    /// cannot keep its fill by chance.
+    fn fill(&self, offset: usize) -> u8 {
+        let x = (self.id << 32 | offset as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (x >> 56) as u8
+    }
+}
+
+/// One run with one seed; panics name the seed and the step.
+struct Stress {
+    seed: u64,
+    step: usize,
+    rng: Rng,
+    live: Vec<Block>,
+}
+
+impl Stress {
+    fn new(seed: u64) -> Self {
+        Stress {
+            seed,
+            step: 0,
+            rng: Rng::new(seed),
+            live: Vec::with_capacity(MAX_LIVE),
+        }
+    }
+
+    fn fail(&self, what: &str, block: &Block) -> ! {
+        panic!(
+            "{} at {:p} ({:?}), seed {:#x}, step {}",
+            what, block.ptr, block.layout, self.seed, self.step
+        );
+    }
+
+    /// A layout of a random size class, or too large for all of them.
+    fn random_layout(&mut self) -> Layout {
+        let class = self.rng.range(0, BLOCK_SIZES.len());
+        let (min, max) = match class {
+            0 => (1, BLOCK_SIZES[0]),
+            _ if class == BLOCK_SIZES.len() => (BLOCK_SIZES[class - 1] + 1, MAX_SIZE),
+            _ => (BLOCK_SIZES[class - 1] + 1, BLOCK_SIZES[class]),
+        };
+        let size = self.rng.range(min, max);
+        let max_shift = max.min(4096).trailing_zeros() as usize;
+        let align = 1 << self.rng.range(0, max_shift);
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    fn allocate(&mut self) {
+        let layout = self.random_layout();
+        let block = Block {
+            ptr: unsafe { alloc(layout) },
+            layout,
+            id: self.step as u64,
+        };
+        if block.ptr.is_null() {
+            self.fail("allocation failed", &block);
+        }
+        if block.ptr as usize % layout.align() != 0 {
+            self.fail("misaligned allocation", &block);
+        }
+        for offset in 0..layout.size() {
+            unsafe { block.ptr.add(offset).write(block.fill(offset)) };
+        }
+        self.live.push(block);
+    }
+
+    fn free(&mut self, index: usize) {
+        let block = self.live.swap_remove(index);
+        self.verify(&block);
+        unsafe { dealloc(block.ptr, block.layout) };
+    }
+
+    /// A block that shares memory with another one has lost its fill.
+    fn verify(&self, block: &Block) {
+        let mut bytes = block.bytes().iter().enumerate();
+        if bytes.any(|(offset, &byte)| byte != block.fill(offset)) {
+            self.fail("overlapping allocation", block);
+        }
+    }
+
+    fn run(mut self) {
+        while self.step < STEPS {
+            let free =
+                self.live.len() == MAX_LIVE || (!self.live.is_empty() && self.rng.next() % 2 == 0);
+            if free {
+                let index = self.rng.range(0, self.live.len() - 1);
+                self.free(index);
+            } else {
+                self.allocate();
+            }
+            if self.step % 1000 == 0 {
+                self.live.iter().for_each(|block| self.verify(block));
+            }
+            self.step += 1;
+        }
+        while !self.live.is_empty() {
+            self.free(self.live.len() - 1);
+        }
+    }
+}
+
+fn parse_seed(seed: &str) -> u64 {
+    let seed = seed.trim();
+    match seed.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => seed.parse(),
+    }
+    .expect("HEAP_STRESS_SEED is not a number")
+}
+
+#[test_case]
+fn test_random_allocations() {
+    let before = allocator::stats();
+    match option_env!("HEAP_STRESS_SEED") {
+        Some(seed) => Stress::new(parse_seed(seed)).run(),
+        None => SEEDS.iter().for_each(|&seed| Stress::new(seed).run()),
+    }
+
+    let after = allocator::stats();
+    for (old, new) in before.classes.iter().zip(after.classes.iter()) {
+        assert!(
+            new.allocations > old.allocations,
+            "size class {} was not used",
+            new.block_size
+        );
+    }
+    assert!(after.fallback_allocations > before.fallback_allocations);
+}
@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::{
+    allocator::{init_heap, HEAP_START},
+    memory::{self, BootInfoFrameAllocator},
+};
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        OffsetPageTable, Page, PageSize, PageTableFlags, Size2MiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+static MAPPER: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+    *MAPPER.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(unsafe { PHYS_MEM_OFFSET })
+}
+
+fn is_huge(mapper: &OffsetPageTable, addr: VirtAddr) -> bool {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, .. } => !matches!(frame, MappedFrame::Size4KiB(_)),
+        _ => false,
+    }
+}
+
+#[test_case]
+fn test_translate_physical_memory_window() {
+    let guard = MAPPER.lock();
+    let (mapper, _) = guard.as_ref().unwrap();
+
+    // the bootloader maps the physical memory window with huge pages
+    for &phys in &[0xb8000u64, 0x20_1008, 0x40_0000, 0x7f_fff8] {
+        let virt = phys_mem_offset() + phys;
+        let translated = unsafe { memory::virttual_to_physical_addr(virt, phys_mem_offset()) };
+        assert_eq!(Some(PhysAddr::new(phys)), translated);
+        assert_eq!(translated, mapper.translate_addr(virt));
+    }
+    assert!(is_huge(mapper, phys_mem_offset()));
+}
+
+#[test_case]
+fn test_heap_is_huge_mapped() {
+    let guard = MAPPER.lock();
+    let (mapper, _) = guard.as_ref().unwrap();
+
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    assert!(is_huge(mapper, heap_start));
+    let translated = unsafe { memory::virttual_to_physical_addr(heap_start, phys_mem_offset()) };
+    assert_eq!(mapper.translate_addr(heap_start), translated);
+}
+
+#[test_case]
+fn test_map_physical_range() {
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().unwrap();
+
+    // two 2 MiB pages followed by two 4 KiB pages
+    let start = VirtAddr::new(0x_6666_0000_0000);
+    let size = 2 * Size2MiB::SIZE + 2 * 4096;
+    let flags = PageTableFlags::PRESENT;
+    unsafe {
+        memory::map_physical_range(
+            mapper,
+            start,
+            PhysAddr::new(0),
+            size,
+            flags,
+            frame_allocator,
+        )
+        .expect("map_physical_range failed");
+    }
+
+    assert!(is_huge(mapper, start));
+    assert!(is_huge(mapper, start + Size2MiB::SIZE));
+    assert!(!is_huge(mapper, start + 2 * Size2MiB::SIZE));
+    assert!(mapper.translate_addr(start + size).is_none());
+
+    for &phys in &[0xb8000u64, 0x30_0010, size - 8] {
+        let translated =
+            unsafe { memory::virttual_to_physical_addr(start + phys, phys_mem_offset()) };
+        assert_eq!(Some(PhysAddr::new(phys)), translated);
+        // the new mapping and the physical memory window see the same memory
+        let ptr: *const u64 = (start + phys).as_ptr();
+        let window: *const u64 = (phys_mem_offset() + phys).as_ptr();
+        assert_eq!(unsafe { window.read_volatile() }, unsafe {
+            ptr.read_volatile()
+        });
+    }
+}
+
+#[test_case]
+fn test_huge_example_mapping() {
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().unwrap();
+
+    let page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(0x_6666_4000_0000));
+    memory::create_example_mapping(page, mapper, frame_allocator);
+
+    let vga = page.start_address() + 0xb8000u64;
+    assert_eq!(Some(PhysAddr::new(0xb8000)), mapper.translate_addr(vga));
+}
+
+#[test_case]
+fn test_huge_frame_allocation() {
+    let mut guard = MAPPER.lock();
+    let (_, frame_allocator) = guard.as_mut().unwrap();
+    let free = frame_allocator.free_frames();
+
+    let frame = frame_allocator.allocate_huge_frame().unwrap();
+    assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(free - 512, frame_allocator.free_frames());
+    unsafe { frame_allocator.deallocate_huge_frame(frame) };
+    assert_eq!(free, frame_allocator.free_frames());
+}
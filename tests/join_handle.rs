@@ -0,0 +1,142 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::rc::Rc;
+use bootloader::{entry_point, BootInfo};
+use common::poll_once;
+use core::{
+    cell::{Cell, RefCell},
+    future::pending,
+    panic::PanicInfo,
+    task::Poll,
+};
+use toy_os::{
+    allocator::init_heap,
+    memory,
+    task::{executor::Executor, join::JoinError, yield_now, Priority, Task},
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+/// Sets its flag when dropped.
+struct DropFlag(Rc<Cell<bool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.set(true);
+    }
+}
+
+#[test_case]
+fn test_output() {
+    let mut executor = Executor::new();
+    let result = Rc::new(Cell::new(None));
+
+    let answer = executor.spawn_with_handle(async {
+        yield_now().await;
+        6 * 7
+    });
+    let result_clone = result.clone();
+    executor.spawn(Task::new(async move {
+        result_clone.set(Some(answer.await));
+    }));
+    executor.run_ready_tasks();
+
+    assert_eq!(Some(Ok(42)), result.get());
+}
+
+#[test_case]
+fn test_priority() {
+    let mut executor = Executor::new();
+    let order = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+    for &(name, priority) in [("low", Priority::Low), ("high", Priority::High)].iter() {
+        let order = order.clone();
+        let (task, handle) = Task::joinable(async move { order.borrow_mut().push(name) }, priority);
+        executor.spawn(task);
+        handle.detach();
+    }
+    executor.run_ready_tasks();
+    assert_eq!(["high", "low"], order.borrow()[..]);
+}
+
+#[test_case]
+fn test_abort() {
+    let mut executor = Executor::new();
+    let dropped = Rc::new(Cell::new(false));
+
+    let guard = DropFlag(dropped.clone());
+    let mut handle = executor.spawn_with_handle(async move {
+        let _guard = guard;
+        pending::<()>().await;
+    });
+    executor.run_ready_tasks();
+    assert!(!handle.is_finished());
+    assert_eq!(Poll::Pending, poll_once(&mut handle));
+
+    handle.abort();
+    executor.run_ready_tasks();
+    assert!(dropped.get());
+    assert_eq!(Poll::Ready(Err(JoinError::Aborted)), poll_once(&mut handle));
+}
+
+#[test_case]
+fn test_abort_after_finish() {
+    let mut executor = Executor::new();
+    let mut handle = executor.spawn_with_handle(async { 1 });
+    executor.run_ready_tasks();
+    handle.abort();
+    assert_eq!(Poll::Ready(Ok(1)), poll_once(&mut handle));
+}
+
+#[test_case]
+fn test_detach() {
+    let mut executor = Executor::new();
+    let done = Rc::new(Cell::new(false));
+    let done_clone = done.clone();
+    executor
+        .spawn_with_handle(async move {
+            yield_now().await;
+            done_clone.set(true);
+        })
+        .detach();
+    executor.run_ready_tasks();
+    assert!(done.get());
+}
+
+#[test_case]
+fn test_cancelled_with_executor() {
+    let mut executor = Executor::new();
+    let mut handle = executor.spawn_with_handle(pending::<()>());
+    executor.run_ready_tasks();
+    drop(executor);
+    assert_eq!(
+        Poll::Ready(Err(JoinError::Cancelled)),
+        poll_once(&mut handle)
+    );
+}
@@ -0,0 +1,206 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::{
+    allocator::{init_heap, reserve_heap},
+    memory::{
+        self,
+        mmio::{self, CacheMode},
+        vma::{VmaError, VmaKind, VmaManager},
+        BootInfoFrameAllocator,
+    },
+};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, OffsetPageTable, PageTableFlags, Translate},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+struct Kernel {
+    vmas: VmaManager,
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+}
+
+static KERNEL: Mutex<Option<Kernel>> = Mutex::new(None);
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+/// The VGA text buffer, the one device every test machine has.
+const VGA_BUFFER: u64 = 0xb8000;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    let mut vmas = VmaManager::new(phys_mem_offset, &boot_info.memory_map);
+    reserve_heap(&mut vmas).expect("heap overlaps a protected region");
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+    *KERNEL.lock() = Some(Kernel {
+        vmas,
+        mapper,
+        frame_allocator,
+    });
+
+    // the tests leave areas behind in the shared `VmaManager`
+    toy_os::disable_leak_check();
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn flags(mapper: &OffsetPageTable, addr: VirtAddr) -> PageTableFlags {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:?} is not mapped", addr),
+    }
+}
+
+#[test_case]
+fn test_pat_layout() {
+    if !mmio::supports_pat() {
+        return;
+    }
+    let pat = mmio::read_pat();
+    // entry 0 write-back, 1 write-combining, 2 write-through, 3 uncached
+    assert_eq!(0x0004_0106, pat & 0xffff_ffff);
+    assert_eq!(pat & 0xffff_ffff, pat >> 32);
+}
+
+#[test_case]
+fn test_cache_mode_flags() {
+    let cache_bits = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    assert!(CacheMode::WriteBack.flags().is_empty());
+    assert_eq!(cache_bits, CacheMode::Uncached.flags());
+    if mmio::supports_pat() {
+        assert_eq!(
+            PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining.flags()
+        );
+        assert_eq!(PageTableFlags::NO_CACHE, CacheMode::WriteThrough.flags());
+    }
+}
+
+#[test_case]
+fn test_map_mmio() {
+    let mut guard = KERNEL.lock();
+    let kernel = guard.as_mut().unwrap();
+    let phys_mem_offset = VirtAddr::new(unsafe { PHYS_MEM_OFFSET });
+
+    // the second character cell, so the mapping starts mid-page
+    let phys = PhysAddr::new(VGA_BUFFER + 2);
+    let addr = unsafe {
+        kernel.vmas.map_mmio(
+            phys,
+            2,
+            CacheMode::Uncached,
+            "vga",
+            &mut kernel.mapper,
+            &mut kernel.frame_allocator,
+        )
+    }
+    .unwrap();
+    assert_eq!(phys.as_u64() % 4096, addr.as_u64() % 4096);
+    assert_eq!(Some(phys), kernel.mapper.translate_addr(addr));
+
+    let area = kernel.vmas.find(addr).unwrap();
+    assert_eq!(VmaKind::Mmio, area.kind);
+    assert_eq!(4096, area.size());
+
+    let flags = flags(&kernel.mapper, addr);
+    assert!(flags.contains(
+        PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+    ));
+
+    // uncached writes are visible through the physical memory window
+    let ptr: *mut u16 = addr.as_mut_ptr();
+    let window: *const u16 = (phys_mem_offset + phys.as_u64()).as_ptr();
+    unsafe {
+        ptr.write_volatile(0x0f21);
+        assert_eq!(0x0f21, window.read_volatile());
+    }
+
+    kernel.vmas.unmap_mmio(addr, &mut kernel.mapper).unwrap();
+    assert!(kernel.mapper.translate_addr(addr).is_none());
+    assert!(kernel.vmas.find(addr).is_none());
+    assert!(matches!(
+        kernel.vmas.unmap_mmio(addr, &mut kernel.mapper),
+        Err(VmaError::NotFound)
+    ));
+}
+
+#[test_case]
+fn test_map_mmio_write_combining() {
+    let mut guard = KERNEL.lock();
+    let kernel = guard.as_mut().unwrap();
+
+    let size = 80 * 25 * 2;
+    let addr = unsafe {
+        kernel.vmas.map_mmio(
+            PhysAddr::new(VGA_BUFFER),
+            size,
+            CacheMode::WriteCombining,
+            "vga",
+            &mut kernel.mapper,
+            &mut kernel.frame_allocator,
+        )
+    }
+    .unwrap();
+    let cache_bits = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    assert_eq!(
+        CacheMode::WriteCombining.flags(),
+        flags(&kernel.mapper, addr) & cache_bits
+    );
+    assert_eq!(
+        Some(PhysAddr::new(VGA_BUFFER + size - 1)),
+        kernel.mapper.translate_addr(addr + (size - 1))
+    );
+    kernel.vmas.unmap_mmio(addr, &mut kernel.mapper).unwrap();
+}
+
+#[test_case]
+fn test_map_mmio_errors() {
+    let mut guard = KERNEL.lock();
+    let kernel = guard.as_mut().unwrap();
+
+    assert!(matches!(
+        unsafe {
+            kernel.vmas.map_mmio(
+                PhysAddr::new(VGA_BUFFER),
+                0,
+                CacheMode::Uncached,
+                "empty",
+                &mut kernel.mapper,
+                &mut kernel.frame_allocator,
+            )
+        },
+        Err(VmaError::Invalid)
+    ));
+
+    let reserved = kernel.vmas.reserve(4096, "reserved").unwrap();
+    assert!(matches!(
+        kernel.vmas.unmap_mmio(reserved, &mut kernel.mapper),
+        Err(VmaError::NotMmio)
+    ));
+}
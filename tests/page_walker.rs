@@ -0,0 +1,167 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::{
+    allocator::{init_heap, HEAP_SIZE, HEAP_START},
+    memory::{
+        self,
+        walker::{self, Change, Mapping, Output},
+        BootInfoFrameAllocator,
+    },
+};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+static MAPPER: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+    *MAPPER.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(unsafe { PHYS_MEM_OFFSET })
+}
+
+fn snapshot() -> alloc::vec::Vec<Mapping> {
+    unsafe { walker::active_mappings(phys_mem_offset()) }
+}
+
+fn find(mappings: &[Mapping], addr: VirtAddr) -> Option<&Mapping> {
+    mappings
+        .iter()
+        .find(|mapping| mapping.start <= addr && addr < mapping.end())
+}
+
+const TEST_PAGES: u64 = 0x_7777_0000_0000;
+
+#[test_case]
+fn test_known_mappings() {
+    let mappings = snapshot();
+    // sorted and without overlaps
+    for pair in mappings.windows(2) {
+        assert!(pair[0].end() <= pair[1].start);
+    }
+
+    let window = find(&mappings, phys_mem_offset()).unwrap();
+    assert_eq!(phys_mem_offset(), window.start);
+    assert!(window.flags.contains(PageTableFlags::HUGE_PAGE));
+    assert!(window.size > 2 * 1024 * 1024);
+
+    let heap = find(&mappings, VirtAddr::new(HEAP_START as u64)).unwrap();
+    assert_eq!(HEAP_SIZE as u64, heap.size);
+    assert!(heap.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!heap.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn test_contiguous_pages_are_merged() {
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().unwrap();
+
+    // three pages mapped to contiguous frames, the last one read-only
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for i in 0..3u64 {
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(TEST_PAGES + i * 4096));
+        let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000 + i * 4096));
+        let flags = if i == 2 {
+            PageTableFlags::PRESENT
+        } else {
+            flags
+        };
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .unwrap()
+                .flush()
+        };
+    }
+
+    let mappings = snapshot();
+    let first = find(&mappings, VirtAddr::new(TEST_PAGES)).unwrap();
+    assert_eq!(VirtAddr::new(TEST_PAGES), first.start);
+    assert_eq!(PhysAddr::new(0xb8000), first.phys);
+    assert_eq!(2 * 4096, first.size);
+    let last = find(&mappings, VirtAddr::new(TEST_PAGES + 2 * 4096)).unwrap();
+    assert_eq!(4096, last.size);
+    assert!(!last.flags.contains(PageTableFlags::WRITABLE));
+
+    walker::dump(&mappings, Output::Serial);
+
+    for i in 0..3u64 {
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(TEST_PAGES + i * 4096));
+        mapper.unmap(page).unwrap().1.flush();
+    }
+}
+
+#[test_case]
+fn test_diff() {
+    let mut guard = MAPPER.lock();
+    let (mapper, frame_allocator) = guard.as_mut().unwrap();
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(TEST_PAGES + 0x10_0000));
+
+    let before = snapshot();
+    let frame = frame_allocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, frame_allocator)
+            .unwrap()
+            .flush()
+    };
+    let after = snapshot();
+
+    let changes = walker::diff(&before, &after);
+    assert_eq!(1, changes.len());
+    match changes[0] {
+        Change::Added(mapping) => {
+            assert_eq!(page.start_address(), mapping.start);
+            assert_eq!(frame.start_address(), mapping.phys);
+            assert_eq!(flags, mapping.flags);
+        }
+        change => panic!("unexpected change {:?}", change),
+    }
+    walker::dump_diff(&before, &after, Output::Serial);
+
+    let (frame, flush) = mapper.unmap(page).unwrap();
+    flush.flush();
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    let unmapped = snapshot();
+    let changes = walker::diff(&after, &unmapped);
+    assert!(
+        matches!(changes[..], [Change::Removed(mapping)] if mapping.start == page.start_address())
+    );
+    assert!(walker::diff(&before, &unmapped).is_empty());
+}
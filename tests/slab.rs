@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use toy_os::{
+    allocator::{
+        init_heap,
+        slab::{SlabBox, SlabCache},
+    },
+    memory,
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+struct Object {
+    id: u64,
+    data: [u64; 7],
+}
+
+impl Object {
+    fn new() -> Self {
+        Object {
+            id: 42,
+            data: [7; 7],
+        }
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+static OBJECTS: SlabCache<Object> = SlabCache::new("objects", Object::new);
+static PAGES: SlabCache<[u8; 2048]> = SlabCache::new("pages", || [0; 2048]);
+
+fn free_frames() -> usize {
+    memory::with_frame_allocator(|frame_allocator| frame_allocator.free_frames())
+}
+
+#[test_case]
+fn test_alloc_uses_constructor() {
+    let free = free_frames();
+    let object = OBJECTS.alloc().unwrap();
+    assert_eq!(42, object.id);
+    assert_eq!([7; 7], object.data);
+
+    let stats = OBJECTS.stats();
+    assert_eq!("objects", stats.name);
+    assert_eq!(64, stats.object_size);
+    assert_eq!((1, 1), (stats.slabs, stats.in_use));
+    assert_eq!(free - 1, free_frames());
+
+    let drops = DROPS.load(Ordering::SeqCst);
+    drop(object);
+    assert_eq!(drops + 1, DROPS.load(Ordering::SeqCst));
+    let stats = OBJECTS.stats();
+    assert_eq!((0, 0), (stats.slabs, stats.in_use));
+    assert_eq!(stats.allocations, stats.frees);
+    // the empty slab went back to the frame allocator
+    assert_eq!(free, free_frames());
+}
+
+#[test_case]
+fn test_multiple_slabs() {
+    let free = free_frames();
+    let per_slab = SlabCache::<Object>::OBJECTS_PER_SLAB;
+    assert!(per_slab > 1);
+
+    let count = 2 * per_slab + 1;
+    let mut objects: Vec<SlabBox<Object>> = (0..count as u64)
+        .map(|id| OBJECTS.alloc_with(Object { id, data: [id; 7] }).unwrap())
+        .collect();
+    assert_eq!(3, OBJECTS.stats().slabs);
+    assert_eq!(free - 3, free_frames());
+
+    let mut addrs: Vec<u64> = objects
+        .iter()
+        .map(|object| SlabBox::addr(object).as_u64())
+        .collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    assert_eq!(count, addrs.len());
+    for (id, object) in objects.iter().enumerate() {
+        assert_eq!(id as u64, object.id);
+        assert_eq!([id as u64; 7], object.data);
+    }
+
+    // freeing every other object keeps the slabs, reusing the slots
+    // does not need new ones
+    let kept: Vec<_> = objects.drain(..).step_by(2).collect();
+    assert_eq!(3, OBJECTS.stats().slabs);
+    let refill: Vec<_> = (0..count / 2).map(|_| OBJECTS.alloc().unwrap()).collect();
+    assert_eq!(3, OBJECTS.stats().slabs);
+
+    drop(kept);
+    drop(refill);
+    assert_eq!(0, OBJECTS.stats().slabs);
+    assert_eq!(free, free_frames());
+}
+
+#[test_case]
+fn test_large_objects() {
+    assert_eq!(1, SlabCache::<[u8; 2048]>::OBJECTS_PER_SLAB);
+    let mut a = PAGES.alloc().unwrap();
+    let b = PAGES.alloc().unwrap();
+    a[2047] = 1;
+    assert_eq!(0, b[2047]);
+    assert_eq!(2, PAGES.stats().slabs);
+    drop(a);
+    drop(b);
+    assert_eq!(0, PAGES.stats().slabs);
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::{
+    allocator::{init_heap, reserve_heap},
+    memory::{self, vma::VmaManager, walker},
+    smp,
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(kernel_main);
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    unsafe { memory::sections::protect_kernel(&mut mapper, &boot_info.memory_map) }
+        .expect("kernel remapping failed");
+
+    let mut vmas = VmaManager::new(phys_mem_offset, &boot_info.memory_map);
+    reserve_heap(&mut vmas).expect("heap overlaps a protected region");
+    smp::init(boot_info, &mut vmas, &mut mapper, &mut frame_allocator).expect("smp init failed");
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+// QEMU runs the tests with `-smp 4`
+#[test_case]
+fn test_cpu_count() {
+    assert_eq!(4, smp::cpu_count());
+}
+
+#[test_case]
+fn test_all_aps_checked_in() {
+    assert_eq!(smp::cpu_count(), smp::online_cpus());
+}
+
+#[test_case]
+fn test_bsp_percpu_data() {
+    let percpu = smp::percpu::current();
+    assert_eq!(0, percpu.cpu_id);
+    assert_eq!(smp::local_apic().unwrap().id(), percpu.apic_id);
+}
+
+#[test_case]
+fn test_no_page_writable_and_executable() {
+    // includes the AP trampoline, if it were still mapped
+    let phys_mem_offset = VirtAddr::new(unsafe { PHYS_MEM_OFFSET });
+    for mapping in unsafe { walker::active_mappings(phys_mem_offset) } {
+        assert!(
+            !mapping.flags.contains(PageTableFlags::WRITABLE)
+                || mapping.flags.contains(PageTableFlags::NO_EXECUTE),
+            "writable and executable: {}",
+            mapping
+        );
+    }
+}
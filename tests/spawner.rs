@@ -0,0 +1,151 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{rc::Rc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    cell::{Cell, RefCell},
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use toy_os::{
+    allocator::init_heap,
+    memory,
+    task::{
+        executor::{Executor, SpawnError},
+        spawner::IRQ_SPAWN_QUEUE_SIZE,
+        Priority, Task,
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_spawn_from_task() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    let order_clone = order.clone();
+    executor.spawn(Task::new(async move {
+        order_clone.borrow_mut().push("parent");
+        let order = order_clone.clone();
+        let child = spawner.clone();
+        spawner.spawn(Task::new(async move {
+            order.borrow_mut().push("child");
+            child.spawn(Task::new(async move {
+                order.borrow_mut().push("grandchild");
+            }));
+        }));
+    }));
+    executor.run_ready_tasks();
+
+    assert_eq!(["parent", "child", "grandchild"], order.borrow()[..]);
+}
+
+#[test_case]
+fn test_spawn_priority() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    for &(name, priority) in [("low", Priority::Low), ("high", Priority::High)].iter() {
+        let order = order.clone();
+        spawner.spawn(Task::with_priority(
+            async move { order.borrow_mut().push(name) },
+            priority,
+        ));
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(["high", "low"], order.borrow()[..]);
+}
+
+#[test_case]
+fn test_spawn_with_handle() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = Rc::new(Cell::new(None));
+
+    let result_clone = result.clone();
+    executor.spawn(Task::new(async move {
+        let answer = spawner.spawn_with_handle(async { 6 * 7 });
+        result_clone.set(Some(answer.await));
+    }));
+    executor.run_ready_tasks();
+
+    assert_eq!(Some(Ok(42)), result.get());
+}
+
+#[test_case]
+fn test_spawner_outlives_executor() {
+    let executor = Executor::new();
+    let spawner = executor.spawner();
+    drop(executor);
+    spawner.spawn(Task::new(async {}));
+}
+
+static IRQ_SUM: AtomicU64 = AtomicU64::new(0);
+
+fn add_to_sum(arg: u64) -> Task {
+    Task::new(async move {
+        IRQ_SUM.fetch_add(arg, Ordering::SeqCst);
+    })
+}
+
+#[test_case]
+fn test_irq_spawn() {
+    let mut executor = Executor::new();
+    let irq_spawner = executor.irq_spawner();
+    IRQ_SUM.store(0, Ordering::SeqCst);
+
+    for arg in 1..=3 {
+        irq_spawner.spawn(add_to_sum, arg).unwrap();
+    }
+    assert_eq!(0, IRQ_SUM.load(Ordering::SeqCst));
+    executor.run_ready_tasks();
+    assert_eq!(6, IRQ_SUM.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_irq_spawn_bounded() {
+    let mut executor = Executor::new();
+    let irq_spawner = executor.irq_spawner();
+    IRQ_SUM.store(0, Ordering::SeqCst);
+
+    for _ in 0..IRQ_SPAWN_QUEUE_SIZE {
+        irq_spawner.spawn(add_to_sum, 1).unwrap();
+    }
+    match irq_spawner.spawn(add_to_sum, 1) {
+        Err(SpawnError::QueueFull) => {}
+        other => panic!("expected QueueFull, got {:?}", other),
+    }
+
+    executor.run_ready_tasks();
+    assert_eq!(IRQ_SPAWN_QUEUE_SIZE as u64, IRQ_SUM.load(Ordering::SeqCst));
+    assert!(irq_spawner.spawn(add_to_sum, 1).is_ok());
+}
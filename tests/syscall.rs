@@ -0,0 +1,182 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_os::{
+    allocator::{init_heap, reserve_heap},
+    memory::{self, vma::VmaManager, USER_SPACE_START},
+    smp,
+    syscall::{self, SyscallError},
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags},
+    VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+const USER_CODE: usize = USER_SPACE_START;
+const USER_STACK: usize = USER_SPACE_START + 0x10_0000;
+/// A page that is user accessible in its level 1 entry only, in a level 4
+/// slot of its own.
+const KERNEL_PARENT_PAGE: usize = USER_SPACE_START + 0x80_0000_0000;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    let mut vmas = VmaManager::new(phys_mem_offset, &boot_info.memory_map);
+    reserve_heap(&mut vmas).expect("heap overlaps a protected region");
+    smp::init(boot_info, &mut vmas, &mut mapper, &mut frame_allocator).expect("smp init failed");
+
+    // one page for the test programs and one for their stack
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for &addr in [USER_CODE, USER_STACK].iter() {
+        let page = Page::containing_address(VirtAddr::new(addr as u64));
+        let frame = frame_allocator.allocate_frame().unwrap();
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, &mut frame_allocator)
+                .unwrap()
+                .flush()
+        };
+    }
+    let page = Page::containing_address(VirtAddr::new(KERNEL_PARENT_PAGE as u64));
+    let frame = frame_allocator.allocate_frame().unwrap();
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper
+            .map_to_with_table_flags(page, frame, flags, parent_flags, &mut frame_allocator)
+            .unwrap()
+            .flush()
+    };
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+/// Copies `code` to the user code page and runs it in ring 3.
+fn run_user_program(code: &[u8]) -> u64 {
+    unsafe {
+        let code_ptr = USER_CODE as *mut u8;
+        core::ptr::copy_nonoverlapping(code.as_ptr(), code_ptr, code.len());
+        syscall::enter_user_mode(
+            VirtAddr::new(USER_CODE as u64),
+            VirtAddr::new((USER_STACK + 4096) as u64),
+        )
+    }
+}
+
+#[test_case]
+fn test_write_yield_sleep_exit() {
+    // mov eax, SYS_WRITE; mov edi, FD_SERIAL; lea rsi, [rip + msg]; mov edx, 21; syscall
+    // mov r12, rax
+    // mov eax, SYS_YIELD; syscall
+    // mov eax, SYS_SLEEP; mov edi, 10; syscall
+    // mov eax, SYS_EXIT; mov rdi, r12; syscall
+    // ud2
+    // msg: "hello from user mode\n"
+    let code = [
+        0xb8, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x02, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x35, 0x29, 0x00,
+        0x00, 0x00, 0xba, 0x15, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x49, 0x89, 0xc4, 0xb8, 0x02, 0x00,
+        0x00, 0x00, 0x0f, 0x05, 0xb8, 0x03, 0x00, 0x00, 0x00, 0xbf, 0x0a, 0x00, 0x00, 0x00, 0x0f,
+        0x05, 0xb8, 0x01, 0x00, 0x00, 0x00, 0x4c, 0x89, 0xe7, 0x0f, 0x05, 0x0f, 0x0b, b'h', b'e',
+        b'l', b'l', b'o', b' ', b'f', b'r', b'o', b'm', b' ', b'u', b's', b'e', b'r', b' ', b'm',
+        b'o', b'd', b'e', b'\n',
+    ];
+    let start = toy_os::interrupts::ticks();
+    // exit code is the return value of write
+    assert_eq!(21, run_user_program(&code));
+    assert!(toy_os::interrupts::ticks() > start);
+}
+
+#[test_case]
+fn test_unknown_syscall() {
+    // mov eax, 99; syscall
+    // mov rdi, rax; mov eax, SYS_EXIT; syscall
+    // ud2
+    let code = [
+        0xb8, 0x63, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x89, 0xc7, 0xb8, 0x01, 0x00, 0x00, 0x00,
+        0x0f, 0x05, 0x0f, 0x0b,
+    ];
+    assert_eq!(
+        SyscallError::NoSys.as_return_value(),
+        run_user_program(&code)
+    );
+}
+
+#[test_case]
+fn test_write_rejects_kernel_pointer() {
+    // mov eax, SYS_WRITE; mov edi, FD_SERIAL; mov rsi, 0x1000; mov edx, 8; syscall
+    // mov rdi, rax; mov eax, SYS_EXIT; syscall
+    // ud2
+    let code = [
+        0xb8, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x02, 0x00, 0x00, 0x00, 0x48, 0xc7, 0xc6, 0x00, 0x10,
+        0x00, 0x00, 0xba, 0x08, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x89, 0xc7, 0xb8, 0x01, 0x00,
+        0x00, 0x00, 0x0f, 0x05, 0x0f, 0x0b,
+    ];
+    assert_eq!(
+        SyscallError::Fault.as_return_value(),
+        run_user_program(&code)
+    );
+}
+
+/// A program that writes `len` bytes from `buf` to the serial port and
+/// exits with the result.
+fn write_program(buf: u64, len: u32) -> [u8; 39] {
+    // mov eax, SYS_WRITE; mov edi, FD_SERIAL; mov rsi, buf; mov edx, len; syscall
+    // mov rdi, rax; mov eax, SYS_EXIT; syscall
+    // ud2
+    let mut code = [
+        0xb8, 0x00, 0x00, 0x00, 0x00, 0xbf, 0x02, 0x00, 0x00, 0x00, 0x48, 0xbe, 0, 0, 0, 0, 0, 0,
+        0, 0, 0xba, 0, 0, 0, 0, 0x0f, 0x05, 0x48, 0x89, 0xc7, 0xb8, 0x01, 0x00, 0x00, 0x00, 0x0f,
+        0x05, 0x0f, 0x0b,
+    ];
+    code[12..20].copy_from_slice(&buf.to_le_bytes());
+    code[21..25].copy_from_slice(&len.to_le_bytes());
+    code
+}
+
+#[test_case]
+fn test_write_rejects_unmapped_buffer() {
+    let unmapped = (USER_SPACE_START + 0x20_0000) as u64;
+    assert_eq!(
+        SyscallError::Fault.as_return_value(),
+        run_user_program(&write_program(unmapped, 8))
+    );
+}
+
+#[test_case]
+fn test_write_rejects_buffer_running_into_unmapped_page() {
+    // the page after the code page is not mapped
+    let end_of_code_page = (USER_CODE + 4096 - 4) as u64;
+    assert_eq!(
+        SyscallError::Fault.as_return_value(),
+        run_user_program(&write_program(end_of_code_page, 8))
+    );
+}
+
+#[test_case]
+fn test_write_rejects_page_with_kernel_only_parent() {
+    assert_eq!(
+        SyscallError::Fault.as_return_value(),
+        run_user_program(&write_program(KERNEL_PARENT_PAGE as u64, 8))
+    );
+}
@@ -0,0 +1,387 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use common::poll_once;
+use core::{
+    cell::{Cell, RefCell},
+    panic::PanicInfo,
+    task::{Context, Poll},
+};
+use futures_util::{stream::StreamExt, task::noop_waker_ref};
+use toy_os::{
+    allocator::init_heap,
+    memory,
+    task::{
+        executor::Executor,
+        sync::{mpsc, oneshot, AcquireError, Mutex, Notify, RwLock, Semaphore},
+        yield_now, Task,
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_mutex_across_await() {
+    let mut executor = Executor::new();
+    let counter = Rc::new(Mutex::new(0));
+    for _ in 0..4 {
+        let counter = counter.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..10 {
+                let mut guard = counter.lock().await;
+                let value = *guard;
+                yield_now().await;
+                *guard = value + 1;
+            }
+        }));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(40, *counter.try_lock().unwrap());
+}
+
+#[test_case]
+fn test_mutex_order() {
+    let mut executor = Executor::new();
+    let mutex = Rc::new(Mutex::new(Vec::new()));
+    let guard = mutex.try_lock().unwrap();
+    assert!(mutex.try_lock().is_none());
+    for i in 0..3 {
+        let mutex = mutex.clone();
+        executor.spawn(Task::new(async move {
+            mutex.lock().await.push(i);
+        }));
+    }
+    executor.run_ready_tasks();
+    drop(guard);
+    executor.run_ready_tasks();
+    assert_eq!([0, 1, 2], mutex.try_lock().unwrap()[..]);
+}
+
+#[test_case]
+fn test_rwlock_readers_share() {
+    let mut executor = Executor::new();
+    let lock = Rc::new(RwLock::new(5));
+    let readers = Rc::new(Cell::new(0));
+    let most_readers = Rc::new(Cell::new(0));
+    for _ in 0..3 {
+        let (lock, readers, most_readers) = (lock.clone(), readers.clone(), most_readers.clone());
+        executor.spawn(Task::new(async move {
+            let guard = lock.read().await;
+            readers.set(readers.get() + 1);
+            most_readers.set(most_readers.get().max(readers.get()));
+            yield_now().await;
+            assert_eq!(5, *guard);
+            readers.set(readers.get() - 1);
+        }));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(3, most_readers.get());
+}
+
+#[test_case]
+fn test_rwlock_writer_not_starved() {
+    let mut executor = Executor::new();
+    let lock = Rc::new(RwLock::new(Vec::new()));
+    let reader = lock.try_read().unwrap();
+
+    let writer_lock = lock.clone();
+    executor.spawn(Task::new(async move {
+        writer_lock.write().await.push("writer");
+    }));
+    executor.run_ready_tasks();
+    // a waiting writer holds back new readers
+    assert!(lock.try_read().is_none());
+    let reader_lock = lock.clone();
+    executor.spawn(Task::new(async move {
+        let guard = reader_lock.read().await;
+        assert_eq!(["writer"], guard[..]);
+    }));
+    executor.run_ready_tasks();
+
+    drop(reader);
+    executor.run_ready_tasks();
+    assert_eq!(["writer"], lock.try_write().unwrap()[..]);
+}
+
+#[test_case]
+fn test_semaphore_limits_concurrency() {
+    let mut executor = Executor::new();
+    let semaphore = Rc::new(Semaphore::new(2));
+    let running = Rc::new(Cell::new(0));
+    let most_running = Rc::new(Cell::new(0));
+    for _ in 0..5 {
+        let (semaphore, running, most_running) =
+            (semaphore.clone(), running.clone(), most_running.clone());
+        executor.spawn(Task::new(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            running.set(running.get() + 1);
+            most_running.set(most_running.get().max(running.get()));
+            yield_now().await;
+            running.set(running.get() - 1);
+        }));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(2, most_running.get());
+    assert_eq!(2, semaphore.available_permits());
+}
+
+#[test_case]
+fn test_semaphore_acquire_many_in_order() {
+    let semaphore = Semaphore::new(1);
+    let mut many = semaphore.acquire_many(2);
+    assert!(poll_once(&mut many).is_pending());
+    // queued behind the larger request, although a permit is free
+    let mut one = semaphore.acquire();
+    assert!(poll_once(&mut one).is_pending());
+
+    semaphore.add_permits(1);
+    let permit = match poll_once(&mut many) {
+        Poll::Ready(Ok(permit)) => permit,
+        _ => panic!("acquire_many not granted"),
+    };
+    assert_eq!(2, permit.num_permits());
+    assert!(poll_once(&mut one).is_pending());
+    drop(permit);
+    assert!(matches!(poll_once(&mut one), Poll::Ready(Ok(_))));
+}
+
+#[test_case]
+fn test_semaphore_dropped_waiter() {
+    let semaphore = Semaphore::new(0);
+    let mut first = semaphore.acquire_many(2);
+    let mut second = semaphore.acquire();
+    assert!(poll_once(&mut first).is_pending());
+    assert!(poll_once(&mut second).is_pending());
+
+    semaphore.add_permits(1);
+    assert!(poll_once(&mut second).is_pending());
+    // the first waiter no longer holds back the second
+    drop(first);
+    assert!(matches!(poll_once(&mut second), Poll::Ready(Ok(_))));
+}
+
+#[test_case]
+fn test_semaphore_close() {
+    let semaphore = Semaphore::new(0);
+    let mut waiter = semaphore.acquire();
+    assert!(poll_once(&mut waiter).is_pending());
+    semaphore.close();
+    assert!(matches!(
+        poll_once(&mut waiter),
+        Poll::Ready(Err(AcquireError))
+    ));
+    assert!(semaphore.try_acquire().is_err());
+}
+
+#[test_case]
+fn test_notify_one_remembered() {
+    let mut executor = Executor::new();
+    let notify = Rc::new(Notify::new());
+    let done = Rc::new(Cell::new(false));
+
+    notify.notify_one();
+    notify.notify_one();
+    let (notify_clone, done_clone) = (notify.clone(), done.clone());
+    executor.spawn(Task::new(async move {
+        notify_clone.notified().await;
+        done_clone.set(true);
+        // both calls counted once
+        notify_clone.notified().await;
+        done_clone.set(false);
+    }));
+    executor.run_ready_tasks();
+    assert!(done.get());
+}
+
+#[test_case]
+fn test_notify_waiters() {
+    let mut executor = Executor::new();
+    let notify = Rc::new(Notify::new());
+    let woken = Rc::new(Cell::new(0));
+    for _ in 0..3 {
+        let (notify, woken) = (notify.clone(), woken.clone());
+        executor.spawn(Task::new(async move {
+            notify.notified().await;
+            woken.set(woken.get() + 1);
+        }));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(0, woken.get());
+
+    notify.notify_waiters();
+    executor.run_ready_tasks();
+    assert_eq!(3, woken.get());
+
+    // not remembered
+    let mut notified = notify.notified();
+    assert!(poll_once(&mut notified).is_pending());
+}
+
+#[test_case]
+fn test_notify_passed_on() {
+    let notify = Notify::new();
+    let mut first = notify.notified();
+    let mut second = notify.notified();
+    assert!(poll_once(&mut first).is_pending());
+    assert!(poll_once(&mut second).is_pending());
+
+    notify.notify_one();
+    drop(first);
+    assert!(poll_once(&mut second).is_ready());
+}
+
+#[test_case]
+fn test_oneshot() {
+    let mut executor = Executor::new();
+    let (sender, receiver) = oneshot::channel();
+    let result = Rc::new(Cell::new(None));
+
+    let result_clone = result.clone();
+    executor.spawn(Task::new(async move {
+        result_clone.set(Some(receiver.await));
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(None, result.get());
+
+    sender.send(42).unwrap();
+    executor.run_ready_tasks();
+    assert_eq!(Some(Ok(42)), result.get());
+}
+
+#[test_case]
+fn test_oneshot_dropped() {
+    let (sender, mut receiver) = oneshot::channel::<u32>();
+    assert_eq!(Err(oneshot::TryRecvError::Empty), receiver.try_recv());
+    drop(sender);
+    assert_eq!(
+        Poll::Ready(Err(oneshot::RecvError)),
+        poll_once(&mut receiver)
+    );
+
+    let (sender, receiver) = oneshot::channel();
+    assert!(!sender.is_closed());
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(Err(7), sender.send(7));
+}
+
+#[test_case]
+fn test_mpsc_bounded() {
+    let mut executor = Executor::new();
+    let (sender, mut receiver) = mpsc::channel(2);
+    let sent = Rc::new(Cell::new(0));
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    let sent_clone = sent.clone();
+    executor.spawn(Task::new(async move {
+        for i in 0..5 {
+            sender.send(i).await.unwrap();
+            sent_clone.set(i + 1);
+        }
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(2, sent.get());
+    assert_eq!(Ok(0), receiver.try_recv());
+
+    let received_clone = received.clone();
+    executor.spawn(Task::new(async move {
+        while let Some(value) = receiver.recv().await {
+            received_clone.borrow_mut().push(value);
+        }
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(5, sent.get());
+    assert_eq!([1, 2, 3, 4], received.borrow()[..]);
+}
+
+#[test_case]
+fn test_mpsc_try_send() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    sender.try_send(1).unwrap();
+    assert_eq!(Err(mpsc::TrySendError::Full(2)), sender.try_send(2));
+    assert_eq!(Ok(1), receiver.try_recv());
+    sender.try_send(3).unwrap();
+
+    receiver.close();
+    assert!(sender.is_closed());
+    assert_eq!(Err(mpsc::TrySendError::Closed(4)), sender.try_send(4));
+    // queued before closing
+    assert_eq!(Ok(3), receiver.try_recv());
+    assert_eq!(Err(mpsc::TryRecvError::Disconnected), receiver.try_recv());
+}
+
+#[test_case]
+fn test_mpsc_close_wakes_senders() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    sender.try_send(1).unwrap();
+    let mut send = Box::pin(sender.send(2));
+    assert!(poll_once(&mut send).is_pending());
+    receiver.close();
+    assert_eq!(Poll::Ready(Err(mpsc::SendError(2))), poll_once(&mut send));
+}
+
+#[test_case]
+fn test_mpsc_unbounded_stream() {
+    let mut executor = Executor::new();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let total = Rc::new(Cell::new(0));
+
+    let total_clone = total.clone();
+    executor.spawn(Task::new(async move {
+        let values: Vec<u32> = receiver.collect().await;
+        total_clone.set(values.iter().sum());
+    }));
+    for _ in 0..3 {
+        let sender = sender.clone();
+        executor.spawn(Task::new(async move {
+            for i in 1..=100 {
+                sender.send(i).unwrap();
+                yield_now().await;
+            }
+        }));
+    }
+    drop(sender);
+    executor.run_ready_tasks();
+    assert_eq!(3 * 5050, total.get());
+}
+
+#[test_case]
+fn test_mpsc_senders_dropped() {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    sender.send(1).unwrap();
+    drop(sender);
+    assert_eq!(Ok(1), receiver.try_recv());
+    assert_eq!(Err(mpsc::TryRecvError::Disconnected), receiver.try_recv());
+    assert_eq!(
+        Poll::Ready(None),
+        receiver.poll_recv(&mut Context::from_waker(noop_waker_ref()))
+    );
+}
@@ -0,0 +1,155 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use toy_os::{
+    allocator::{init_heap, reserve_heap},
+    interrupts,
+    memory::{self, vma::VmaManager},
+    smp::{self, percpu},
+    task::{executor::Executor, Task},
+    thread,
+};
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    toy_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    let mut vmas = VmaManager::new(phys_mem_offset, &boot_info.memory_map);
+    reserve_heap(&mut vmas).expect("heap overlaps a protected region");
+    smp::init(boot_info, &mut vmas, &mut mapper, &mut frame_allocator).expect("smp init failed");
+    memory::init_global_frame_allocator(frame_allocator);
+    thread::init(&mut vmas).expect("thread init failed");
+
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_spawn_and_join() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn_thread("counter", move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .expect("spawn failed")
+        })
+        .collect();
+    for &id in threads.iter() {
+        thread::join(id);
+        assert_eq!(None, thread::name(id));
+    }
+    assert_eq!(4, counter.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_preemption() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static SPINS: AtomicUsize = AtomicUsize::new(0);
+
+    // never yields, so only the timer gets the CPU back
+    let spinner = thread::spawn_thread("spinner", || {
+        while !STOP.load(Ordering::SeqCst) {
+            SPINS.fetch_add(1, Ordering::Relaxed);
+        }
+    })
+    .expect("spawn failed");
+    thread::sleep(100);
+    STOP.store(true, Ordering::SeqCst);
+    thread::join(spinner);
+    assert!(SPINS.load(Ordering::Relaxed) > 0);
+}
+
+#[test_case]
+fn test_sleep() {
+    let start = interrupts::ticks();
+    thread::sleep(200);
+    assert!(interrupts::ticks() - start >= interrupts::ms_to_ticks(200));
+}
+
+#[test_case]
+fn test_yield_interleaves() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let threads: Vec<_> = ["a", "b"]
+        .iter()
+        .map(|&name| {
+            let log = log.clone();
+            thread::spawn_thread(name, move || {
+                for _ in 0..5 {
+                    log.lock().push(name);
+                    thread::yield_now();
+                }
+            })
+            .expect("spawn failed")
+        })
+        .collect();
+    threads.iter().for_each(|&id| thread::join(id));
+
+    let log = log.lock();
+    assert_eq!(10, log.len());
+    let first_b = log.iter().position(|&name| name == "b").unwrap();
+    let last_a = log.iter().rposition(|&name| name == "a").unwrap();
+    assert!(first_b < last_a, "threads did not interleave: {:?}", *log);
+}
+
+#[test_case]
+fn test_kernel_stack_per_thread() {
+    let before = percpu::current().kernel_stack_top();
+    let threads: Vec<_> = (1..=2u64)
+        .map(|i| {
+            thread::spawn_thread("stack top", move || {
+                let top = VirtAddr::new(i * 0x10_0000);
+                percpu::current().set_kernel_stack_top(top);
+                for _ in 0..3 {
+                    thread::yield_now();
+                    assert_eq!(top, percpu::current().kernel_stack_top());
+                }
+            })
+            .expect("spawn failed")
+        })
+        .collect();
+    threads.iter().for_each(|&id| thread::join(id));
+    assert_eq!(before, percpu::current().kernel_stack_top());
+}
+
+#[test_case]
+fn test_executor_in_thread() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let executor = thread::spawn_thread("executor", || {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            DONE.store(true, Ordering::SeqCst);
+        }));
+        executor.run_ready_tasks();
+    })
+    .expect("spawn failed");
+    thread::join(executor);
+    assert!(DONE.load(Ordering::SeqCst));
+}
@@ -0,0 +1,242 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use toy_os::{
+    allocator::{init_heap, reserve_heap, HEAP_START},
+    memory::{
+        self,
+        vma::{VmaError, VmaKind, VmaManager, KERNEL_VMA_END, KERNEL_VMA_START},
+        BootInfoFrameAllocator, USER_SPACE_START,
+    },
+};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, OffsetPageTable, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+struct Kernel {
+    vmas: VmaManager,
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+}
+
+static KERNEL: Mutex<Option<Kernel>> = Mutex::new(None);
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+#[no_mangle]
+pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+
+    let mut vmas = VmaManager::new(phys_mem_offset, &boot_info.memory_map);
+    reserve_heap(&mut vmas).expect("heap overlaps a protected region");
+
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+    *KERNEL.lock() = Some(Kernel {
+        vmas,
+        mapper,
+        frame_allocator,
+    });
+
+    // the tests leave areas behind in the shared `VmaManager`
+    toy_os::disable_leak_check();
+    test_main();
+    toy_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_os::test_panic_handler(info)
+}
+
+fn kernel_main_addr() -> VirtAddr {
+    VirtAddr::from_ptr(kernel_main as *const ())
+}
+
+#[test_case]
+fn test_reserve() {
+    let mut guard = KERNEL.lock();
+    let vmas = &mut guard.as_mut().unwrap().vmas;
+
+    let a = vmas.reserve(3 * 4096, "a").unwrap();
+    let b = vmas.reserve(4096, "b").unwrap();
+    for &addr in &[a, b] {
+        assert!(addr.is_aligned(4096u64));
+        assert!(KERNEL_VMA_START <= addr.as_u64() && addr.as_u64() < KERNEL_VMA_END);
+    }
+    // areas never touch, there is always a guard page in between
+    assert!(a + 3 * 4096u64 < b || b + 4096u64 < a);
+
+    let area = vmas.find(a + 5000u64).unwrap();
+    assert_eq!(("a", a, 3 * 4096), (area.name, area.start, area.size()));
+    assert_eq!(VmaKind::Reserved, area.kind);
+    assert!(vmas.find(a + 3 * 4096u64).is_none());
+
+    assert!(matches!(vmas.reserve(0, "empty"), Err(VmaError::Invalid)));
+    assert!(matches!(vmas.reserve(100, "odd"), Err(VmaError::Invalid)));
+    assert!(matches!(
+        vmas.reserve(KERNEL_VMA_END - KERNEL_VMA_START, "huge"),
+        Err(VmaError::OutOfSpace)
+    ));
+}
+
+#[test_case]
+fn test_reserve_fixed_rejects_overlaps() {
+    let mut guard = KERNEL.lock();
+    let vmas = &mut guard.as_mut().unwrap().vmas;
+    let phys_mem_offset = VirtAddr::new(unsafe { PHYS_MEM_OFFSET });
+
+    let overlaps = [
+        (phys_mem_offset + 0x10_0000u64, "physical memory window"),
+        (kernel_main_addr().align_down(4096u64), "kernel image"),
+        (VirtAddr::new(USER_SPACE_START as u64), "user space"),
+        (VirtAddr::new(HEAP_START as u64), "heap"),
+    ];
+    for &(start, name) in overlaps.iter() {
+        match vmas.reserve_fixed(start, 4096, "test") {
+            Err(VmaError::Overlap(other)) => assert_eq!(name, other),
+            other => panic!("expected overlap with {}, got {:?}", name, other),
+        }
+    }
+
+    let start = VirtAddr::new(0x_6000_0000_0000);
+    vmas.reserve_fixed(start, 2 * 4096, "fixed").unwrap();
+    assert!(matches!(
+        vmas.reserve_fixed(start + 4096u64, 4096, "again"),
+        Err(VmaError::Overlap("fixed"))
+    ));
+}
+
+#[test_case]
+fn test_reserve_fixed_rejects_overflowing_ranges() {
+    let mut guard = KERNEL.lock();
+    let vmas = &mut guard.as_mut().unwrap().vmas;
+
+    let last_page = VirtAddr::new(0x_ffff_ffff_ffff_f000);
+    assert!(matches!(
+        vmas.reserve_fixed(last_page, 2 * 4096, "wraps"),
+        Err(VmaError::Invalid)
+    ));
+    // ends in the non-canonical hole
+    let below_hole = VirtAddr::new(0x_7fff_ffff_f000);
+    assert!(matches!(
+        vmas.reserve_fixed(below_hole, 2 * 4096, "hole"),
+        Err(VmaError::Invalid)
+    ));
+}
+
+#[test_case]
+fn test_map_anonymous_and_unmap() {
+    let mut guard = KERNEL.lock();
+    let kernel = guard.as_mut().unwrap();
+    let flags = PageTableFlags::WRITABLE;
+
+    // the first mapping may allocate page tables, which are never freed
+    let start = kernel
+        .vmas
+        .map_anonymous(
+            4096,
+            flags,
+            "warm up",
+            &mut kernel.mapper,
+            &mut kernel.frame_allocator,
+        )
+        .unwrap();
+    kernel
+        .vmas
+        .unmap(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+        .unwrap();
+
+    let free = kernel.frame_allocator.free_frames();
+    let size = 4 * 4096;
+    let start = kernel
+        .vmas
+        .map_anonymous(
+            size,
+            flags,
+            "anon",
+            &mut kernel.mapper,
+            &mut kernel.frame_allocator,
+        )
+        .unwrap();
+    assert_eq!(free - 4, kernel.frame_allocator.free_frames());
+
+    let ptr: *mut u64 = start.as_mut_ptr();
+    let words = (size / 8) as usize;
+    for i in 0..words {
+        // fresh pages are zeroed
+        assert_eq!(0, unsafe { ptr.add(i).read_volatile() });
+        unsafe { ptr.add(i).write_volatile(i as u64) };
+    }
+
+    kernel
+        .vmas
+        .unmap(start, &mut kernel.mapper, &mut kernel.frame_allocator)
+        .unwrap();
+    assert!(kernel.mapper.translate_addr(start).is_none());
+    assert!(kernel.vmas.find(start).is_none());
+    assert_eq!(free, kernel.frame_allocator.free_frames());
+
+    assert!(matches!(
+        kernel
+            .vmas
+            .unmap(start, &mut kernel.mapper, &mut kernel.frame_allocator),
+        Err(VmaError::NotFound)
+    ));
+}
+
+#[test_case]
+fn test_protect() {
+    let mut guard = KERNEL.lock();
+    let kernel = guard.as_mut().unwrap();
+
+    let start = kernel
+        .vmas
+        .map_anonymous(
+            2 * 4096,
+            PageTableFlags::WRITABLE,
+            "protected",
+            &mut kernel.mapper,
+            &mut kernel.frame_allocator,
+        )
+        .unwrap();
+    kernel
+        .vmas
+        .protect(start, PageTableFlags::empty(), &mut kernel.mapper)
+        .unwrap();
+
+    for &addr in &[start, start + 4096u64] {
+        match kernel.mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => {
+                assert!(flags.contains(PageTableFlags::PRESENT));
+                assert!(!flags.contains(PageTableFlags::WRITABLE));
+            }
+            _ => panic!("{:?} is not mapped", addr),
+        }
+    }
+    let area = kernel.vmas.find(start).unwrap();
+    assert!(!area.flags.contains(PageTableFlags::WRITABLE));
+
+    // reserved areas are mapped by their owners
+    let reserved = kernel.vmas.reserve(4096, "reserved").unwrap();
+    assert!(matches!(
+        kernel
+            .vmas
+            .protect(reserved, PageTableFlags::WRITABLE, &mut kernel.mapper),
+        Err(VmaError::NotAnonymous)
+    ));
+}